@@ -0,0 +1,246 @@
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+
+use crate::mastodon::MentionAccount;
+
+/// Why a request was turned down
+#[derive(Debug, PartialEq)]
+pub enum Denial {
+    Bot,
+    DenyList,
+    NotAllowed,
+    Blocked,
+}
+
+impl fmt::Display for Denial {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Denial::Bot => "account is a bot",
+            Denial::DenyList => "account is in the deny list",
+            Denial::NotAllowed => "account is not in the allow list",
+            Denial::Blocked => "account is blocked or muted",
+        })
+    }
+}
+
+/// Full `user@domain` address of an account, in lowercase
+fn full_acct(acct: &str, local_domain: &str) -> String {
+    let acct = acct.trim().trim_start_matches('@').to_lowercase();
+    if acct.contains('@') {
+        acct
+    } else {
+        // local accounts come without a domain
+        format!("{acct}@{local_domain}")
+    }
+}
+
+/// A set of accounts and domains
+#[derive(Debug, Default)]
+struct Rules {
+    accounts: HashSet<String>,
+    domains: HashSet<String>,
+}
+
+impl Rules {
+    /// One account (`user@domain`) or domain per line, `#` starts a comment
+    fn parse(text: &str, local_domain: &str) -> Self {
+        let mut rules = Self::default();
+        for entry in text
+            .lines()
+            .map(|l| l.split('#').next().unwrap_or_default().trim())
+            .filter(|l| !l.is_empty())
+        {
+            if entry.contains('@') {
+                rules.accounts.insert(full_acct(entry, local_domain));
+            } else {
+                rules.domains.insert(entry.to_lowercase());
+            }
+        }
+        rules
+    }
+
+    /// Whether the account or its domain (or any parent domain) are in the set
+    fn matches(&self, acct: &str) -> bool {
+        if self.accounts.contains(acct) {
+            return true;
+        }
+        let mut domain = acct.rsplit('@').next().unwrap_or_default();
+        loop {
+            if self.domains.contains(domain) {
+                return true;
+            }
+            match domain.split_once('.') {
+                Some((_, parent)) => domain = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+/// Rules kept in a file, which is reloaded whenever it changes
+#[derive(Debug)]
+struct RuleFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    rules: Rules,
+}
+
+impl RuleFile {
+    fn load(path: &Path, local_domain: &str) -> Result<Self> {
+        let modified = fs::metadata(path)?.modified().ok();
+        let text =
+            fs::read_to_string(path).with_context(|| format!("Can't read {}", path.display()))?;
+        Ok(Self {
+            path: path.into(),
+            modified,
+            rules: Rules::parse(&text, local_domain),
+        })
+    }
+
+    fn refresh(&mut self, local_domain: &str) {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified.is_some() && modified != self.modified {
+            match Self::load(&self.path, local_domain) {
+                Ok(file) => {
+                    log::info!("Reloaded {}", self.path.display());
+                    *self = file;
+                }
+                // keep the old rules rather than letting everyone in
+                Err(e) => log::warn!("Couldn't reload {}: {e:#}", self.path.display()),
+            }
+        }
+    }
+}
+
+/// Decides who the bot answers to
+#[derive(Debug)]
+pub struct AccessPolicy {
+    local_domain: String,
+    allow_bots: bool,
+    allow: Option<RuleFile>,
+    deny: Option<RuleFile>,
+    /// Accounts and domains blocked or muted by the bot account on its instance
+    blocked: Rules,
+}
+
+impl AccessPolicy {
+    pub fn new(
+        local_domain: &str,
+        allow_bots: bool,
+        allow_file: Option<&Path>,
+        deny_file: Option<&Path>,
+    ) -> Result<Self> {
+        let local_domain = local_domain.to_lowercase();
+        Ok(Self {
+            allow: allow_file
+                .map(|path| RuleFile::load(path, &local_domain))
+                .transpose()?,
+            deny: deny_file
+                .map(|path| RuleFile::load(path, &local_domain))
+                .transpose()?,
+            local_domain,
+            allow_bots,
+            blocked: Rules::default(),
+        })
+    }
+
+    /// Replace the accounts and domains blocked on the instance
+    pub fn set_blocked(&mut self, accounts: &[String], domains: &[String]) {
+        self.blocked = Rules {
+            accounts: accounts
+                .iter()
+                .map(|a| full_acct(a, &self.local_domain))
+                .collect(),
+            domains: domains.iter().map(|d| d.to_lowercase()).collect(),
+        };
+    }
+
+    /// Check whether an account may use the bot (reloading the lists if needed)
+    pub fn check(&mut self, account: &MentionAccount) -> Result<(), Denial> {
+        let acct = full_acct(&account.acct, &self.local_domain);
+
+        for file in self.allow.iter_mut().chain(self.deny.iter_mut()) {
+            file.refresh(&self.local_domain);
+        }
+
+        if self.blocked.matches(&acct) {
+            Err(Denial::Blocked)
+        } else if self.deny.as_ref().is_some_and(|f| f.rules.matches(&acct)) {
+            Err(Denial::DenyList)
+        } else if self.allow.as_ref().is_some_and(|f| !f.rules.matches(&acct)) {
+            Err(Denial::NotAllowed)
+        } else if account.bot && !self.allow_bots {
+            Err(Denial::Bot)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(acct: &str, bot: bool) -> MentionAccount {
+        MentionAccount {
+            acct: acct.into(),
+            url: format!("https://example.com/@{acct}"),
+            display_name: acct.into(),
+            bot,
+        }
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules = Rules::parse(
+            "# comment\n@Spammer@example.com\n@local  # on our instance\n\nbad.social\n",
+            "orca.social",
+        );
+        assert!(rules.matches("spammer@example.com"));
+        assert!(rules.matches("local@orca.social"));
+        assert!(rules.matches("anyone@bad.social"));
+        assert!(rules.matches("anyone@sub.bad.social"));
+        assert!(!rules.matches("anyone@notbad.social"));
+        assert!(!rules.matches("someone@example.com"));
+    }
+
+    #[test]
+    fn test_access_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let deny = dir.path().join("deny.txt");
+        fs::write(&deny, "troll@example.com\n").unwrap();
+
+        let mut policy = AccessPolicy::new("orca.social", false, None, Some(&deny)).unwrap();
+        assert_eq!(policy.check(&account("someone", false)), Ok(()));
+        assert_eq!(policy.check(&account("someone", true)), Err(Denial::Bot));
+        assert_eq!(
+            policy.check(&account("Troll@example.com", false)),
+            Err(Denial::DenyList)
+        );
+
+        policy.set_blocked(&["someone".into()], &["example.org".into()]);
+        assert_eq!(
+            policy.check(&account("someone", false)),
+            Err(Denial::Blocked)
+        );
+        assert_eq!(
+            policy.check(&account("else@example.org", false)),
+            Err(Denial::Blocked)
+        );
+
+        let allow = dir.path().join("allow.txt");
+        fs::write(&allow, "friends.social\n").unwrap();
+        let mut policy = AccessPolicy::new("orca.social", true, Some(&allow), None).unwrap();
+        assert_eq!(policy.check(&account("a@friends.social", true)), Ok(()));
+        assert_eq!(
+            policy.check(&account("a@example.com", false)),
+            Err(Denial::NotAllowed)
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Sample rate of the Varvara audio device
+pub const SAMPLE_RATE: u32 = 44100;
+
+/// Number of (interleaved) channels in each audio stream
+pub const NUM_CHANNELS: u16 = 2;
+
+/// Level above which the limiter starts compressing the signal
+const LIMITER_THRESHOLD: f32 = 0.8;
+
+/// Peak level under which a mixdown is considered silent (-80 dBFS)
+const SILENCE_THRESHOLD: f32 = 1e-4;
+
+/// Settings for the mixing stage, which combines the audio channels into a single stream
+#[derive(Debug, Clone)]
+pub struct MixConfig {
+    /// Gain applied to the mixdown (dB)
+    pub master_gain: f32,
+    /// Whether the soft limiter should be applied
+    pub limiter: bool,
+    /// Integrated loudness the result is normalized to when encoding (LUFS, EBU R128)
+    pub loudness_target: Option<f32>,
+}
+
+impl Default for MixConfig {
+    fn default() -> Self {
+        Self {
+            master_gain: 0.0,
+            limiter: true,
+            loudness_target: None,
+        }
+    }
+}
+
+impl MixConfig {
+    /// Add `input` to the `mixdown` buffer
+    pub fn add(&self, mixdown: &mut [f32], input: &[f32]) {
+        for (v, s) in mixdown.iter_mut().zip(input) {
+            *v += s;
+        }
+    }
+
+    /// Apply master gain and limiter to a mixed-down buffer
+    pub fn master(&self, mixdown: &mut [f32]) {
+        let gain = db_to_amplitude(self.master_gain);
+        for v in mixdown.iter_mut() {
+            *v *= gain;
+            if self.limiter {
+                *v = soft_limit(*v);
+            }
+        }
+    }
+}
+
+/// Peak/RMS statistics of the mixdown
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct AudioStats {
+    pub peak: f32,
+    sum_squares: f64,
+    num_samples: u64,
+}
+
+impl AudioStats {
+    pub fn update(&mut self, samples: &[f32]) {
+        for s in samples {
+            self.peak = self.peak.max(s.abs());
+            self.sum_squares += (*s as f64) * (*s as f64);
+        }
+        self.num_samples += samples.len() as u64;
+    }
+
+    pub fn rms(&self) -> f32 {
+        if self.num_samples == 0 {
+            0.0
+        } else {
+            (self.sum_squares / self.num_samples as f64).sqrt() as f32
+        }
+    }
+
+    pub fn is_silent(&self) -> bool {
+        self.peak < SILENCE_THRESHOLD
+    }
+}
+
+impl fmt::Display for AudioStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_silent() {
+            write!(f, "silent")
+        } else {
+            write!(
+                f,
+                "peak {:.1} dBFS, RMS {:.1} dBFS",
+                amplitude_to_db(self.peak),
+                amplitude_to_db(self.rms())
+            )
+        }
+    }
+}
+
+/// Writes interleaved stereo `f32` samples to a WAV file
+pub struct WavWriter {
+    writer: BufWriter<File>,
+    data_len: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut s = Self {
+            writer: BufWriter::new(File::create(path)?),
+            data_len: 0,
+        };
+        // sizes will be filled in by `finish`
+        s.write_header()?;
+        Ok(s)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let block_align = NUM_CHANNELS * 4;
+        let w = &mut self.writer;
+        w.write_all(b"RIFF")?;
+        w.write_all(&(36 + self.data_len).to_le_bytes())?;
+        w.write_all(b"WAVEfmt ")?;
+        w.write_all(&16u32.to_le_bytes())?;
+        // IEEE float
+        w.write_all(&3u16.to_le_bytes())?;
+        w.write_all(&NUM_CHANNELS.to_le_bytes())?;
+        w.write_all(&SAMPLE_RATE.to_le_bytes())?;
+        w.write_all(&(SAMPLE_RATE * block_align as u32).to_le_bytes())?;
+        w.write_all(&block_align.to_le_bytes())?;
+        w.write_all(&32u16.to_le_bytes())?;
+        w.write_all(b"data")?;
+        w.write_all(&self.data_len.to_le_bytes())
+    }
+
+    pub fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        for s in samples {
+            self.writer.write_all(&s.to_le_bytes())?;
+        }
+        self.data_len += (samples.len() * 4) as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.writer.flush()
+    }
+}
+
+pub fn amplitude_to_db(amplitude: f32) -> f32 {
+    20.0 * amplitude.log10()
+}
+
+pub fn db_to_amplitude(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Leave samples under the threshold untouched and smoothly compress the rest, so that the output never exceeds 1.0
+pub fn soft_limit(sample: f32) -> f32 {
+    let level = sample.abs();
+    if level <= LIMITER_THRESHOLD {
+        sample
+    } else {
+        let headroom = 1.0 - LIMITER_THRESHOLD;
+        let compressed =
+            LIMITER_THRESHOLD + headroom * ((level - LIMITER_THRESHOLD) / headroom).tanh();
+        compressed.copysign(sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_soft_limit() {
+        assert_eq!(soft_limit(0.5), 0.5);
+        assert_eq!(soft_limit(-0.8), -0.8);
+        assert!(soft_limit(0.9) < 0.9);
+        assert!(soft_limit(0.9) > 0.8);
+        assert!(soft_limit(4.0) <= 1.0);
+        assert!(soft_limit(-4.0) >= -1.0);
+        // monotonic
+        assert!(soft_limit(2.0) > soft_limit(1.5));
+    }
+
+    #[test]
+    fn test_master() {
+        let config = MixConfig {
+            master_gain: -6.0,
+            limiter: false,
+            loudness_target: None,
+        };
+        let mut buf = [1.0, -0.5];
+        config.master(&mut buf);
+        assert!((buf[0] - 0.501).abs() < 0.001);
+        assert!((buf[1] + 0.2505).abs() < 0.001);
+
+        let config = MixConfig::default();
+        let mut buf = [0.0; 2];
+        config.add(&mut buf, &[1.0, 1.0]);
+        config.add(&mut buf, &[1.0, -0.25]);
+        config.master(&mut buf);
+        assert!(buf[0] <= 1.0);
+        assert_eq!(buf[1], 0.75);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut stats = AudioStats::default();
+        assert!(stats.is_silent());
+        assert_eq!(stats.rms(), 0.0);
+
+        stats.update(&[0.0; 1470]);
+        assert!(stats.is_silent());
+
+        stats.update(&[0.5, -0.5, 0.5, -0.5]);
+        assert!(!stats.is_silent());
+        assert_eq!(stats.peak, 0.5);
+        assert!(stats.rms() > 0.0 && stats.rms() < 0.5);
+    }
+
+    #[test]
+    fn test_wav_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.wav");
+
+        let mut wav = WavWriter::create(&path).unwrap();
+        wav.write(&[0.0, 0.5, -0.5, 1.0]).unwrap();
+        wav.write(&[0.25; 6]).unwrap();
+        wav.finish().unwrap();
+
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data.len(), 44 + 10 * 4);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), 36 + 40);
+        assert_eq!(u32::from_le_bytes(data[40..44].try_into().unwrap()), 40);
+        assert_eq!(f32::from_le_bytes(data[48..52].try_into().unwrap()), 0.5);
+    }
+}
@@ -0,0 +1,216 @@
+use std::{
+    fs::{self, File},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Name of the file which holds the metadata of each entry
+const META_FILE: &str = "meta.json";
+
+/// Hex-encoded SHA-256 of some data
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Results of previous jobs, stored on disk under the hash of whatever produced them
+pub struct ResultCache {
+    dir: PathBuf,
+    /// Mixed into every key, so that changing e.g. the ROM invalidates everything
+    salt: String,
+    max_size: u64,
+    max_age: Duration,
+}
+
+/// An entry, as found on disk
+struct Entry {
+    path: PathBuf,
+    size: u64,
+    last_used: SystemTime,
+}
+
+impl ResultCache {
+    pub fn new(
+        dir: impl AsRef<Path>,
+        salt: &str,
+        max_size: u64,
+        max_age: Duration,
+    ) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir: dir.as_ref().into(),
+            salt: salt.into(),
+            max_size,
+            max_age,
+        })
+    }
+
+    pub fn key(&self, content: &str) -> String {
+        sha256_hex(format!("{}\n{content}", self.salt).as_bytes())
+    }
+
+    /// Copy the files of an entry into `dest` and return its metadata (if found)
+    pub fn get<M: DeserializeOwned>(&self, key: &str, dest: &Path) -> Result<Option<M>> {
+        let entry_dir = self.dir.join(key);
+        let meta = match fs::read_to_string(entry_dir.join(META_FILE)) {
+            Ok(meta) => serde_json::from_str(&meta)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        for file in fs::read_dir(&entry_dir)? {
+            let file = file?;
+            if file.file_name() != META_FILE {
+                fs::copy(file.path(), dest.join(file.file_name()))?;
+            }
+        }
+
+        // keep the popular ones around
+        File::options()
+            .write(true)
+            .open(entry_dir.join(META_FILE))?
+            .set_modified(SystemTime::now())?;
+
+        Ok(Some(meta))
+    }
+
+    /// Store some files under `key`, making room for them if needed
+    pub fn put<M: Serialize>(&self, key: &str, files: &[&Path], meta: &M) -> Result<()> {
+        let entry_dir = self.dir.join(key);
+        if entry_dir.exists() {
+            // another worker got there first
+            return Ok(());
+        }
+
+        // put everything together first, so that nobody sees a partial entry
+        let tmp_dir = tempfile::tempdir_in(&self.dir)?;
+        for file in files {
+            if let Some(name) = file.file_name() {
+                fs::copy(file, tmp_dir.path().join(name))?;
+            }
+        }
+        fs::write(tmp_dir.path().join(META_FILE), serde_json::to_string(meta)?)?;
+
+        // (the temporary directory is gone by the time it's dropped, which is fine)
+        fs::rename(tmp_dir.path(), &entry_dir)?;
+
+        self.evict()
+    }
+
+    fn entries(&self) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for dir in fs::read_dir(&self.dir)? {
+            let path = dir?.path();
+            if path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+            {
+                // still being put together
+                continue;
+            }
+            let Ok(last_used) = fs::metadata(path.join(META_FILE)).and_then(|m| m.modified())
+            else {
+                // not an entry (or not a complete one yet)
+                continue;
+            };
+            let mut size = 0;
+            for file in fs::read_dir(&path)? {
+                size += file?.metadata()?.len();
+            }
+            entries.push(Entry {
+                path,
+                size,
+                last_used,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Drop entries which are too old, then the least recently used ones until everything fits
+    pub fn evict(&self) -> Result<()> {
+        let mut entries = self.entries()?;
+        entries.sort_by_key(|e| e.last_used);
+
+        let now = SystemTime::now();
+        let mut total: u64 = entries.iter().map(|e| e.size).sum();
+        for entry in entries {
+            let expired = now
+                .duration_since(entry.last_used)
+                .is_ok_and(|age| age > self.max_age);
+            if !expired && total <= self.max_size {
+                continue;
+            }
+
+            log::debug!("Evicting {} from the cache", entry.path.display());
+            match fs::remove_dir_all(&entry.path) {
+                Ok(()) => total -= entry.size,
+                // some other worker may have been quicker
+                Err(e) if e.kind() == ErrorKind::NotFound => total -= entry.size,
+                Err(e) => log::warn!("Can't evict {}: {e}", entry.path.display()),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = tempfile::tempdir().unwrap();
+        let file = files.path().join("out.mp4");
+        fs::write(&file, [0u8; 100]).unwrap();
+
+        let cache = ResultCache::new(dir.path(), "rom", 250, Duration::from_secs(3600)).unwrap();
+        let key = cache.key("..C8..");
+        assert_ne!(key, cache.key("..C9.."));
+        assert!(cache.get::<u32>(&key, files.path()).unwrap().is_none());
+
+        cache.put(&key, &[&file], &42).unwrap();
+        let dest = tempfile::tempdir().unwrap();
+        assert_eq!(cache.get::<u32>(&key, dest.path()).unwrap(), Some(42));
+        assert!(dest.path().join("out.mp4").exists());
+
+        // the oldest entry has to go to make room
+        cache.put(&cache.key("1"), &[&file], &1).unwrap();
+        File::options()
+            .write(true)
+            .open(dir.path().join(&key).join(META_FILE))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(60))
+            .unwrap();
+        cache.put(&cache.key("2"), &[&file], &2).unwrap();
+        assert!(cache.get::<u32>(&key, dest.path()).unwrap().is_none());
+        assert_eq!(
+            cache.get::<u32>(&cache.key("2"), dest.path()).unwrap(),
+            Some(2)
+        );
+
+        // expired entries go away without anything new being stored
+        File::options()
+            .write(true)
+            .open(dir.path().join(cache.key("2")).join(META_FILE))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(7200))
+            .unwrap();
+        cache.evict().unwrap();
+        assert!(cache
+            .get::<u32>(&cache.key("2"), dest.path())
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            cache.get::<u32>(&cache.key("1"), dest.path()).unwrap(),
+            Some(1)
+        );
+    }
+}
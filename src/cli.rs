@@ -1,6 +1,9 @@
 use std::path::PathBuf;
 
-use clap::{Args, Parser, Subcommand};
+use clap::{builder::RangedU64ValueParser, Args, Parser, Subcommand, ValueEnum};
+use megalodon::{entities::StatusVisibility, SNS};
+
+use crate::audio::MixConfig;
 
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
@@ -12,7 +15,7 @@ pub(crate) struct Cli {
 #[derive(Debug, Subcommand)]
 pub(crate) enum SubCommands {
     /// Run the bot, fetching notifications and processing them
-    Run(RunArgs),
+    Run(Box<RunArgs>),
 
     /// Run the ROM, based on user input
     Exec {
@@ -37,10 +40,26 @@ pub(crate) enum SubCommands {
         /// Use the native Uxn implementation
         #[clap(long)]
         native: bool,
+
+        #[command(flatten)]
+        mix: MixArgs,
+
+        /// Write each audio channel (and the mix) as a separate WAV file into this directory
+        #[clap(long)]
+        stems: Option<PathBuf>,
             
         /// Arguments to pass into the VM
         #[arg(last = true)]
         args: Vec<String>,
+    },
+
+    /// Copy a CSV history file into a new SQLite database
+    MigrateHistory {
+        /// CSV history file
+        csv: PathBuf,
+
+        /// SQLite database to create
+        database: PathBuf,
     }
 }
 
@@ -61,6 +80,10 @@ pub(crate) struct RunArgs {
     #[clap(env, long, default_value_t = 10)]
     pub(crate) max_requests_hour: usize,
 
+    /// Also count requests which were rejected or failed towards the limits above
+    #[clap(env, long)]
+    pub(crate) rate_limit_failures: bool,
+
     /// Maximum length of lines
     #[clap(env, long, default_value_t = 32)]
     pub(crate) max_line_length: u8,
@@ -69,14 +92,134 @@ pub(crate) struct RunArgs {
     #[clap(env, long, default_value_t = 32)]
     pub(crate) max_num_lines: u8,
 
-    /// Location of history file
-    #[clap(env, long, default_value = "history.csv")]
-    pub(crate) history_file: PathBuf,
+    /// Maximum size of the output video (KB), re-encoding it with lower quality if needed
+    #[clap(env, long, default_value_t = 40 * 1024, value_parser = clap::value_parser!(u64).range(1..=1024 * 1024 * 1024))]
+    pub(crate) max_video_size: u64,
+
+    /// Maximum duration of the output video (seconds)
+    #[clap(env, long)]
+    pub(crate) max_video_duration: Option<u64>,
+
+    /// Time (seconds) of the frame to use as the video thumbnail, instead of picking the busiest one
+    #[clap(env, long)]
+    pub(crate) thumbnail_time: Option<f64>,
+
+    /// Location of history file (defaults to `history.csv`, or `history.db` for SQLite)
+    #[clap(env, long)]
+    pub(crate) history_file: Option<PathBuf>,
+
+    /// Format of the history file
+    #[clap(env, long, value_enum, default_value_t = HistoryFormat::Csv)]
+    pub(crate) history_format: HistoryFormat,
+
+    /// Only keep the history of this many days in memory (CSV only, everything is kept by default)
+    #[clap(env, long, value_parser = clap::value_parser!(u64).range(1..=100 * 365))]
+    pub(crate) history_max_age: Option<u64>,
+
+    /// Archive the history file once it gets this large (MB, CSV only)
+    #[clap(env, long, value_parser = clap::value_parser!(u64).range(1..=1024 * 1024))]
+    pub(crate) history_rotate_size: Option<u64>,
+
+    /// Archive the history file at the start of every month (CSV only)
+    #[clap(env, long)]
+    pub(crate) history_rotate_monthly: bool,
+
+    /// Location of the file which keeps track of the last processed notification (defaults to the history file, with a `.cursor` extension)
+    #[clap(env, long)]
+    pub(crate) cursor_file: Option<PathBuf>,
+
+    /// Location of the file which keeps track of accepted jobs, so that they survive restarts (defaults to the history file, with a `.jobs` extension)
+    #[clap(env, long)]
+    pub(crate) jobs_file: Option<PathBuf>,
 
     /// Tag which should be mentioned for the code to be run
     #[clap(env, long, default_value = "run")]
     pub(crate) run_tag: String,    
 
+    /// Don't use the streaming API, just poll for new notifications
+    #[clap(long)]
+    pub(crate) no_streaming: bool,
+
+    /// Maximum interval between checks for new notifications, when not streaming (seconds)
+    #[clap(env, long, default_value_t = 60)]
+    pub(crate) poll_interval: u64,
+
+    /// Maximum visibility of the posts with results (replies are never more visible than the request)
+    #[clap(env, long, value_enum, default_value_t = Visibility::Public)]
+    pub(crate) max_visibility: Visibility,
+
+    /// Maximum visibility of error messages (replies are never more visible than the request)
+    #[clap(env, long, value_enum, default_value_t = Visibility::Direct)]
+    pub(crate) max_message_visibility: Visibility,
+
+    /// Type of server the bot account lives on
+    #[clap(env, long, value_enum, default_value_t = ServerType::Auto)]
+    pub(crate) server_type: ServerType,
+
+    /// Maximum number of retries of failed API calls
+    #[clap(env, long, default_value_t = 5)]
+    pub(crate) api_max_retries: u32,
+
+    /// Delay before the first retry of a failed API call, doubling every time (seconds)
+    #[clap(env, long, default_value_t = 2)]
+    pub(crate) api_retry_delay: u64,
+
+    /// How long to wait for the server to process an uploaded video before giving up (seconds)
+    #[clap(env, long, default_value_t = 300)]
+    pub(crate) media_timeout: u64,
+
+    /// Number of jobs which can run at the same time
+    #[clap(env, long, default_value_t = 2, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub(crate) workers: usize,
+
+    /// Maximum number of requests waiting to be run (the rest are left pending until there's room)
+    #[clap(env, long, default_value_t = 20, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub(crate) queue_size: usize,
+
+    /// Maximum number of requests from the same account waiting to be run
+    #[clap(env, long, default_value_t = 2, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub(crate) max_queued_per_user: usize,
+
+    /// Maximum number of requests accepted in an hour, from all accounts (no limit by default)
+    #[clap(env, long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub(crate) max_jobs_hour: Option<u64>,
+
+    /// What to do with requests which come in when the queue is full or the hourly budget is spent
+    #[clap(env, long, value_enum, default_value_t = WhenBusy::Defer)]
+    pub(crate) when_busy: WhenBusy,
+
+    /// Directory where results are kept, so that identical requests don't have to be run again
+    #[clap(env, long)]
+    pub(crate) cache_dir: Option<PathBuf>,
+
+    /// Maximum size of the result cache (MB)
+    #[clap(env, long, default_value_t = 1024, value_parser = clap::value_parser!(u64).range(1..=1024 * 1024))]
+    pub(crate) cache_max_size: u64,
+
+    /// Results which haven't been requested again in this long are dropped from the cache (days)
+    #[clap(env, long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..=100 * 365))]
+    pub(crate) cache_max_age: u64,
+
+    /// How long running jobs get to finish when the bot is asked to stop (seconds)
+    #[clap(env, long, default_value_t = 30)]
+    pub(crate) shutdown_grace_period: u64,
+
+    /// Answer accounts flagged as bots too (beware of loops with other bots!)
+    #[clap(long)]
+    pub(crate) allow_bots: bool,
+
+    /// File with the only accounts (`user@domain`, or `@user` if local) and domains the bot answers to
+    #[clap(env, long)]
+    pub(crate) allow_list: Option<PathBuf>,
+
+    /// File with accounts and domains the bot never answers to
+    #[clap(env, long)]
+    pub(crate) deny_list: Option<PathBuf>,
+
+    /// TOML file with the messages posted by the bot (and their translations)
+    #[clap(env, long)]
+    pub(crate) templates_file: Option<PathBuf>,
+
     /// Mastodon instance URL
     #[clap(env, long, required = true)]
     pub(crate) mastodon_instance_url: String,
@@ -88,8 +231,108 @@ pub(crate) struct RunArgs {
     /// Use the native Uxn implementation
     #[clap(long)]
     pub(crate) native: bool,
+
+    #[command(flatten)]
+    pub(crate) mix: MixArgs,
         
     /// Arguments to pass into the VM
     #[arg(last = true)]
     pub(crate) args: Vec<String>,
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Args)]
+pub(crate) struct MixArgs {
+    /// Gain applied to the audio mixdown (dB)
+    #[clap(env, long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub(crate) master_gain: f32,
+
+    /// Don't apply the soft limiter to the audio mixdown
+    #[clap(long)]
+    pub(crate) no_limiter: bool,
+
+    /// Normalize audio to this integrated loudness (LUFS, EBU R128)
+    #[clap(env, long, allow_negative_numbers = true)]
+    pub(crate) loudness_target: Option<f32>,
+}
+
+impl RunArgs {
+    pub(crate) fn history_file(&self) -> PathBuf {
+        self.history_file
+            .clone()
+            .unwrap_or_else(|| match self.history_format {
+                HistoryFormat::Csv => "history.csv".into(),
+                HistoryFormat::Sqlite => "history.db".into(),
+            })
+    }
+}
+
+impl MixArgs {
+    pub(crate) fn mix_config(&self) -> MixConfig {
+        MixConfig {
+            master_gain: self.master_gain,
+            limiter: !self.no_limiter,
+            loudness_target: self.loudness_target,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub(crate) enum Visibility {
+    Public,
+    Unlisted,
+    Private,
+    Direct,
+}
+
+impl From<Visibility> for StatusVisibility {
+    fn from(value: Visibility) -> Self {
+        match value {
+            Visibility::Public => StatusVisibility::Public,
+            Visibility::Unlisted => StatusVisibility::Unlisted,
+            Visibility::Private => StatusVisibility::Private,
+            Visibility::Direct => StatusVisibility::Direct,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub(crate) enum HistoryFormat {
+    /// Just the basics, one line per request
+    Csv,
+    /// Also keeps timings and what was run
+    Sqlite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum WhenBusy {
+    /// Leave them pending until there's room
+    Defer,
+    /// Tell the account to try again later
+    Reply,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub(crate) enum ServerType {
+    /// Detect it from the instance's nodeinfo
+    Auto,
+    Mastodon,
+    /// Pleroma and Akkoma
+    Pleroma,
+    Friendica,
+    /// Firefish and other Misskey-family servers
+    Firefish,
+    Gotosocial,
+}
+
+impl From<ServerType> for Option<SNS> {
+    fn from(value: ServerType) -> Self {
+        match value {
+            ServerType::Auto => None,
+            ServerType::Mastodon => Some(SNS::Mastodon),
+            ServerType::Pleroma => Some(SNS::Pleroma),
+            ServerType::Friendica => Some(SNS::Friendica),
+            ServerType::Firefish => Some(SNS::Firefish),
+            ServerType::Gotosocial => Some(SNS::Gotosocial),
+        }
+    }
+}
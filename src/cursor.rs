@@ -0,0 +1,73 @@
+use std::{
+    cmp::Ordering,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+
+use crate::mastodon::compare_ids;
+
+/// Keeps track (on disk) of the last notification which was processed, so that we can resume from there
+pub struct Cursor {
+    file_path: PathBuf,
+    last_id: Option<String>,
+}
+
+impl Cursor {
+    pub fn new(file: impl AsRef<Path>) -> Result<Self> {
+        let file_path = file.as_ref().to_path_buf();
+        let last_id = match fs::read_to_string(&file_path) {
+            Ok(content) => Some(content.trim().to_string()).filter(|id| !id.is_empty()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { file_path, last_id })
+    }
+
+    pub fn last_id(&self) -> Option<&str> {
+        self.last_id.as_deref()
+    }
+
+    /// Move the cursor forward (it never goes back)
+    pub fn advance(&mut self, id: &str) -> Result<()> {
+        if let Some(last_id) = &self.last_id {
+            if compare_ids(id, last_id) != Ordering::Greater {
+                return Ok(());
+            }
+        }
+
+        // write to a temporary file first, so that we never end up with a half-written cursor
+        let mut tmp_path = self.file_path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, id)?;
+        fs::rename(&tmp_path, &self.file_path)?;
+
+        self.last_id = Some(id.into());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.cursor");
+
+        let mut cursor = Cursor::new(&path).unwrap();
+        assert_eq!(cursor.last_id(), None);
+
+        cursor.advance("998").unwrap();
+        cursor.advance("1000").unwrap();
+        // older ones are ignored
+        cursor.advance("999").unwrap();
+        assert_eq!(cursor.last_id(), Some("1000"));
+
+        // resume after restart
+        let cursor = Cursor::new(&path).unwrap();
+        assert_eq!(cursor.last_id(), Some("1000"));
+    }
+}
@@ -1,43 +1,409 @@
-use std::{path::Path, process::Command};
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
 
-pub fn encode<ScreenDir: AsRef<Path>, AudioFile: AsRef<Path>, OutFile: AsRef<Path>>(
+/// Frame rate at which the VM output is rendered
+pub const FRAME_RATE: usize = 60;
+
+/// Audio bitrate used in all encodings (kbps)
+const AUDIO_BITRATE: u32 = 128;
+
+/// Lowest video bitrate we're willing to go down to before reducing resolution (kbps)
+const MIN_VIDEO_BITRATE: u32 = 150;
+
+/// Maximum factor by which the resolution can be reduced
+const MAX_SCALE_DOWN: u16 = 4;
+
+/// Maximum number of encoding passes before giving up
+const MAX_ATTEMPTS: usize = 8;
+
+/// Frame rate of GIF output, which doesn't need to be as smooth
+const GIF_FRAME_RATE: usize = 30;
+
+/// Kind of file which is produced
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutputFormat {
+    /// MP4 video with audio
+    Video,
+    /// Animated GIF, without audio
+    Gif,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Video => "mp4",
+            OutputFormat::Gif => "gif",
+        }
+    }
+}
+
+/// Only every Nth frame is considered when looking for a thumbnail
+const THUMBNAIL_STEP: usize = 10;
+
+/// How the thumbnail frame should be picked
+#[derive(Debug, Clone, Copy)]
+pub enum ThumbnailPick {
+    /// The frame with the most non-empty pixels
+    Busiest,
+    /// The frame at a given time
+    At(Duration),
+}
+
+/// A still image representing the video
+#[derive(Debug)]
+pub struct Thumbnail {
+    pub path: PathBuf,
+    /// Focal point, in Mastodon's coordinates (from -1.0 to 1.0, y pointing up)
+    pub focus: (f32, f32),
+}
+
+/// Constraints that the output file should fit in
+#[derive(Debug, Default, Clone)]
+pub struct EncodingLimits {
+    pub max_bytes: Option<u64>,
+    pub max_duration: Option<Duration>,
+}
+
+/// Parameters used for an encoding pass
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncodingParams {
+    /// Kind of file to produce
+    pub format: OutputFormat,
+    /// Size of the VM screen
+    pub source_size: (u16, u16),
+    /// Factor by which the resolution is divided
+    pub scale_down: u16,
+    /// Video bitrate in kbps (`None` means the encoder's default quality-based mode)
+    pub video_bitrate: Option<u32>,
+    /// Duration of the output
+    pub duration: Duration,
+    /// Integrated loudness to normalize the audio to (LUFS)
+    pub loudness_target: Option<f32>,
+    /// Size of the resulting file, in bytes
+    pub file_size: u64,
+}
+
+impl EncodingParams {
+    pub fn new(
+        format: OutputFormat,
+        source_size: (u16, u16),
+        num_frames: usize,
+        loudness_target: Option<f32>,
+    ) -> Self {
+        Self {
+            format,
+            source_size,
+            scale_down: 1,
+            video_bitrate: None,
+            duration: Duration::from_secs_f64(num_frames as f64 / FRAME_RATE as f64),
+            loudness_target,
+            file_size: 0,
+        }
+    }
+
+    /// Trim the duration, if it exceeds the limits
+    fn limit_duration(self, limits: &EncodingLimits) -> Self {
+        match limits.max_duration {
+            Some(max) => Self {
+                duration: self.duration.min(max),
+                ..self
+            },
+            None => self,
+        }
+    }
+
+    /// Final (even) dimensions of the video
+    pub fn output_size(&self) -> (u16, u16) {
+        let (width, height) = self.source_size;
+        (
+            (width / self.scale_down) & !1,
+            (height / self.scale_down) & !1,
+        )
+    }
+
+    /// Compute the parameters of the next attempt, given a target file size
+    fn shrink(&self, max_bytes: u64) -> Option<Self> {
+        let secs = self.duration.as_secs_f64();
+
+        // bitrate that the whole file should have in order to fit, with some headroom for the container
+        let target = (max_bytes as f64 * 8.0 / 1000.0 / secs * 0.9) as u32;
+        let video_target = target.saturating_sub(AUDIO_BITRATE);
+
+        // what we actually got in the last pass
+        let actual = (self.file_size as f64 * 8.0 / 1000.0 / secs) as u32;
+        let video_actual = actual.saturating_sub(AUDIO_BITRATE);
+
+        // never go up, and make sure we actually move down
+        let next = video_target
+            .min(video_actual * 9 / 10)
+            .min(self.video_bitrate.unwrap_or(u32::MAX));
+
+        if self.format == OutputFormat::Video && next >= MIN_VIDEO_BITRATE {
+            Some(Self {
+                video_bitrate: Some(next),
+                ..self.clone()
+            })
+        } else if self.scale_down < MAX_SCALE_DOWN {
+            // lower resolution, which should make it easier to reach the target bitrate
+            Some(Self {
+                scale_down: self.scale_down * 2,
+                video_bitrate: Some(video_target.max(MIN_VIDEO_BITRATE)),
+                ..self.clone()
+            })
+        } else if (self.format == OutputFormat::Gif || video_target < MIN_VIDEO_BITRATE)
+            && secs > 1.0
+        {
+            // as a last resort, cut the video short
+            Some(Self {
+                duration: self.duration / 2,
+                video_bitrate: Some(MIN_VIDEO_BITRATE),
+                ..self.clone()
+            })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for EncodingParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (width, height) = self.output_size();
+        write!(f, "{} {width}x{height}, ", self.format.extension())?;
+        match (self.format, self.video_bitrate) {
+            (OutputFormat::Gif, _) => write!(f, "{GIF_FRAME_RATE} fps")?,
+            (OutputFormat::Video, Some(bitrate)) => write!(f, "{bitrate} kbps")?,
+            (OutputFormat::Video, None) => write!(f, "default bitrate")?,
+        }
+        write!(
+            f,
+            ", {:.1}s, {} KB",
+            self.duration.as_secs_f64(),
+            self.file_size / 1024
+        )
+    }
+}
+
+/// Encode the video, re-encoding it with lower quality settings until it fits the limits
+pub fn encode_within_limits<
+    ScreenDir: AsRef<Path>,
+    AudioFile: AsRef<Path>,
+    OutFile: AsRef<Path>,
+>(
     screen_dir: ScreenDir,
-    (width, height): (u16, u16),
     audio_file: AudioFile,
     out_file: OutFile,
-) -> Result<()> {
+    params: EncodingParams,
+    limits: &EncodingLimits,
+) -> Result<EncodingParams> {
+    let mut params = params.limit_duration(limits);
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        encode(&screen_dir, &params, &audio_file, &out_file)?;
+        params.file_size = fs::metadata(&out_file)?.len();
+
+        match limits.max_bytes {
+            Some(max_bytes) if params.file_size > max_bytes => {
+                log::info!("Encoding attempt {attempt} ({params}) is over the size limit");
+                params = params
+                    .shrink(max_bytes)
+                    .ok_or_else(|| anyhow!("Can't fit video in {} KB", max_bytes / 1024))?;
+            }
+            _ => return Ok(params),
+        }
+    }
+
+    bail!("Couldn't fit video within limits after {MAX_ATTEMPTS} attempts")
+}
+
+fn frame_path(screen_dir: impl AsRef<Path>, n: usize) -> PathBuf {
+    screen_dir.as_ref().join(format!("out_{n:05}.rgba"))
+}
+
+/// How many pixels differ from the background (top-left pixel), and their centroid in focus coordinates
+fn analyze_frame(frame: &[u8], (width, height): (u16, u16)) -> (usize, (f32, f32)) {
+    let Some(background) = frame.get(0..4) else {
+        return (0, (0.0, 0.0));
+    };
+
+    let (mut filled, mut sum_x, mut sum_y) = (0usize, 0f64, 0f64);
+    for (n, pixel) in frame.chunks_exact(4).enumerate() {
+        if pixel != background {
+            filled += 1;
+            sum_x += (n % width as usize) as f64;
+            sum_y += (n / width as usize) as f64;
+        }
+    }
+
+    if filled == 0 {
+        (0, (0.0, 0.0))
+    } else {
+        let x = sum_x / filled as f64 / width as f64;
+        let y = sum_y / filled as f64 / height as f64;
+        (filled, ((x * 2.0 - 1.0) as f32, (1.0 - y * 2.0) as f32))
+    }
+}
+
+/// Pick a representative frame and save it as a PNG file
+pub fn render_thumbnail(
+    screen_dir: impl AsRef<Path>,
+    params: &EncodingParams,
+    pick: ThumbnailPick,
+    out_file: impl AsRef<Path>,
+) -> Result<Thumbnail> {
+    let num_frames = (params.duration.as_secs_f64() * FRAME_RATE as f64) as usize;
+    let size = params.source_size;
+
+    let (frame_n, focus) = match pick {
+        ThumbnailPick::Busiest => {
+            let mut best = (0, (0, (0.0, 0.0)));
+            for n in (0..num_frames).step_by(THUMBNAIL_STEP) {
+                let stats = analyze_frame(&fs::read(frame_path(&screen_dir, n))?, size);
+                if stats.0 > best.1 .0 {
+                    best = (n, stats);
+                }
+            }
+            (best.0, best.1 .1)
+        }
+        ThumbnailPick::At(time) => {
+            let n = ((time.as_secs_f64() * FRAME_RATE as f64) as usize)
+                .min(num_frames.saturating_sub(1));
+            (
+                n,
+                analyze_frame(&fs::read(frame_path(&screen_dir, n))?, size).1,
+            )
+        }
+    };
+
+    log::debug!("Using frame {frame_n} as thumbnail, focus on {focus:?}");
+
+    let (width, height) = size;
     let out = Command::new("ffmpeg")
         .args([
             "-f",
-            "image2",
+            "rawvideo",
             "-pixel_format",
             "rgba",
             "-video_size",
             &format!("{width}x{height}"),
-            "-framerate",
-            "60",
-            "-c:v",
-            "rawvideo",
             "-i",
-            screen_dir
-                .as_ref()
-                .join("out_%05d.rgba")
+            frame_path(&screen_dir, frame_n)
                 .to_str()
                 .context("Invalid dir name")?,
-            "-ac",
-            "2",
-            "-ar",
-            "44100",
-            "-f",
-            "f32le",
-            "-i",
-            audio_file.as_ref().to_str().context("Invalid file name")?,
-            "-c:v",
-            "libx264",
-            "-c:a",
-            "aac",
+            "-frames:v",
+            "1",
+            "-y",
+            out_file.as_ref().to_str().context("Invalid file name")?,
+        ])
+        .output()
+        .context("Error running FFmpeg")?;
+
+    if out.status.success() {
+        Ok(Thumbnail {
+            path: out_file.as_ref().to_path_buf(),
+            focus,
+        })
+    } else {
+        log::error!("{}", &String::from_utf8_lossy(&out.stderr));
+        Err(anyhow!("Command returned an error"))
+    }
+}
+
+pub fn encode<ScreenDir: AsRef<Path>, AudioFile: AsRef<Path>, OutFile: AsRef<Path>>(
+    screen_dir: ScreenDir,
+    params: &EncodingParams,
+    audio_file: AudioFile,
+    out_file: OutFile,
+) -> Result<()> {
+    let (width, height) = params.source_size;
+    let (out_width, out_height) = params.output_size();
+
+    let mut cmd = Command::new("ffmpeg");
+    cmd.args([
+        "-f",
+        "image2",
+        "-pixel_format",
+        "rgba",
+        "-video_size",
+        &format!("{width}x{height}"),
+        "-framerate",
+        &FRAME_RATE.to_string(),
+        "-c:v",
+        "rawvideo",
+        "-i",
+        screen_dir
+            .as_ref()
+            .join("out_%05d.rgba")
+            .to_str()
+            .context("Invalid dir name")?,
+    ]);
+
+    match params.format {
+        OutputFormat::Video => {
+            cmd.args([
+                "-ac",
+                "2",
+                "-ar",
+                "44100",
+                "-f",
+                "f32le",
+                "-i",
+                audio_file.as_ref().to_str().context("Invalid file name")?,
+                "-t",
+                &format!("{:.3}", params.duration.as_secs_f64()),
+                "-vf",
+                &format!("scale={out_width}:{out_height}:flags=neighbor"),
+                "-c:v",
+                "libx264",
+                "-c:a",
+                "aac",
+                "-b:a",
+                &format!("{AUDIO_BITRATE}k"),
+            ]);
+
+            if let Some(target) = params.loudness_target {
+                // EBU R128 normalization, keeping true peaks below -1 dBTP (loudnorm upsamples, so go back to 44.1 kHz)
+                cmd.args([
+                    "-af",
+                    &format!("loudnorm=I={target}:TP=-1.0:LRA=11,aresample=44100"),
+                ]);
+            }
+
+            if let Some(bitrate) = params.video_bitrate {
+                cmd.args([
+                    "-b:v",
+                    &format!("{bitrate}k"),
+                    "-maxrate",
+                    &format!("{bitrate}k"),
+                    "-bufsize",
+                    &format!("{}k", bitrate * 2),
+                ]);
+            }
+        }
+        OutputFormat::Gif => {
+            // generate an optimal palette from the frames themselves
+            cmd.args([
+                "-t",
+                &format!("{:.3}", params.duration.as_secs_f64()),
+                "-vf",
+                &format!(
+                    "fps={GIF_FRAME_RATE},scale={out_width}:{out_height}:flags=neighbor,\
+                     split[s0][s1];[s0]palettegen[p];[s1][p]paletteuse"
+                ),
+                "-loop",
+                "0",
+            ]);
+        }
+    }
+
+    let out = cmd
+        .args([
             "-y",
             out_file.as_ref().to_str().context("Invalid file name")?,
         ])
@@ -52,3 +418,87 @@ pub fn encode<ScreenDir: AsRef<Path>, AudioFile: AsRef<Path>, OutFile: AsRef<Pat
         Err(anyhow!("Command returned an error"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(file_size: u64) -> EncodingParams {
+        EncodingParams {
+            file_size,
+            ..EncodingParams::new(OutputFormat::Video, (512, 320), 600, None)
+        }
+    }
+
+    #[test]
+    fn test_analyze_frame() {
+        let mut frame = vec![0u8; 4 * 4 * 2];
+        assert_eq!(analyze_frame(&frame, (4, 2)), (0, (0.0, 0.0)));
+
+        // two pixels on the right half of the top row
+        frame[2 * 4..2 * 4 + 4].copy_from_slice(&[255, 255, 255, 255]);
+        frame[3 * 4..3 * 4 + 4].copy_from_slice(&[255, 255, 255, 255]);
+        let (filled, (x, y)) = analyze_frame(&frame, (4, 2));
+        assert_eq!(filled, 2);
+        assert_eq!(x, 0.25);
+        assert_eq!(y, 1.0);
+    }
+
+    #[test]
+    fn test_duration_is_trimmed() {
+        let limits = EncodingLimits {
+            max_bytes: None,
+            max_duration: Some(Duration::from_secs(4)),
+        };
+        let params =
+            EncodingParams::new(OutputFormat::Video, (512, 320), 600, None).limit_duration(&limits);
+        assert_eq!(params.duration, Duration::from_secs(4));
+
+        let params =
+            EncodingParams::new(OutputFormat::Video, (512, 320), 120, None).limit_duration(&limits);
+        assert_eq!(params.duration, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_shrink_lowers_bitrate() {
+        // 10s, 2 MB -> ~1600 kbps
+        let next = params(2_000_000).shrink(1_000_000).unwrap();
+        let bitrate = next.video_bitrate.unwrap();
+        assert!(bitrate < 800 - AUDIO_BITRATE);
+        assert_eq!(next.scale_down, 1);
+
+        // already at a given bitrate, never go back up
+        let next = EncodingParams {
+            video_bitrate: Some(300),
+            ..params(2_000_000)
+        }
+        .shrink(1_000_000)
+        .unwrap();
+        assert!(next.video_bitrate.unwrap() <= 300);
+    }
+
+    #[test]
+    fn test_shrink_scales_then_trims() {
+        // target bitrate too low, so resolution goes down
+        let next = params(1_000_000).shrink(200_000).unwrap();
+        assert_eq!(next.scale_down, 2);
+        assert_eq!(next.output_size(), (256, 160));
+
+        let next = EncodingParams {
+            scale_down: MAX_SCALE_DOWN,
+            ..params(1_000_000)
+        }
+        .shrink(200_000)
+        .unwrap();
+        assert_eq!(next.duration, Duration::from_secs(5));
+
+        // GIFs have no bitrate to play with
+        let next = EncodingParams {
+            format: OutputFormat::Gif,
+            ..params(2_000_000)
+        }
+        .shrink(1_000_000)
+        .unwrap();
+        assert_eq!(next.scale_down, 2);
+    }
+}
@@ -1,48 +1,172 @@
 use std::{
-    fs::File,
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, ErrorKind},
     path::{Path, PathBuf},
+    time::Duration,
 };
 
-use anyhow::Result;
-use chrono::{DateTime, TimeZone, Utc};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Datelike, TimeDelta, Utc};
+use flate2::{write::GzEncoder, Compression};
 use serde::{Deserialize, Serialize};
 
+use crate::{audio::AudioStats, ratelimit::UserRates};
+
+mod sqlite;
+
+pub use sqlite::SqliteHistory;
+
+/// How far back requests are kept track of for rate limiting
+const RATE_WINDOW: TimeDelta = TimeDelta::hours(1);
+
+/// What came out of a request
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The result was posted
+    Posted,
+    /// The code couldn't be parsed
+    ParseError,
+    /// The account sent too many requests
+    RateLimited,
+    /// The bot had too many requests overall
+    Busy,
+    /// The code couldn't be run, or the video couldn't be encoded
+    RunFailed,
+    /// The server couldn't process the video
+    MediaFailed,
+    /// The result couldn't be posted
+    PostFailed,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Posted => "posted",
+            Outcome::ParseError => "parse_error",
+            Outcome::RateLimited => "rate_limited",
+            Outcome::Busy => "busy",
+            Outcome::RunFailed => "run_failed",
+            Outcome::MediaFailed => "media_failed",
+            Outcome::PostFailed => "post_failed",
+        }
+    }
+}
+
+/// A request the bot is done with
+#[derive(Debug)]
+pub struct Record<'t> {
+    pub request_id: &'t str,
+    /// Account which made the request
+    pub user: &'t str,
+    /// When the bot was done with it
+    pub time: DateTime<Utc>,
+    pub outcome: Outcome,
+    /// URL of the reply
+    pub url: Option<&'t str>,
+    /// When the request was posted
+    pub requested_at: Option<DateTime<Utc>>,
+    /// How long running the code and encoding the video took
+    pub run_time: Option<Duration>,
+    /// Hash of the Orca grid
+    pub grid_hash: Option<&'t str>,
+    /// Hash of the ROM which ran it
+    pub rom_hash: Option<&'t str>,
+    pub audio: Option<&'t AudioStats>,
+    /// What went wrong, if anything
+    pub error: Option<&'t str>,
+}
+
+/// Where the bot keeps track of the requests it answered
+pub trait HistoryStore: Send {
+    /// Whether the request has already been answered
+    fn has_request(&self, id: &str) -> Result<bool>;
+
+    /// Whether the result of the request was posted (other outcomes may be replaced later),
+    /// going by the URL of the reply for entries which predate request IDs
+    fn has_result(&self, id: &str, url: &str) -> Result<bool>;
+
+    /// Recent requests of each account which got a result
+    fn rates(&self) -> &UserRates;
+
+    /// Recent requests of each account which were rejected or failed (not because the bot was busy)
+    fn failed_rates(&self) -> &UserRates;
+
+    /// Requests answered before this may have been forgotten, so `has_request` can't tell
+    fn retention_start(&self) -> Option<DateTime<Utc>> {
+        None
+    }
+
+    fn log(&mut self, record: &Record) -> Result<()>;
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LogEntry {
     pub time: DateTime<Utc>,
     pub user: String,
     pub url: String,
+    /// Audio peak level (older entries don't have it)
+    #[serde(default)]
+    pub audio_peak: Option<f32>,
+    /// Audio RMS level
+    #[serde(default)]
+    pub audio_rms: Option<f32>,
+    /// ID of the post which requested it
+    #[serde(default)]
+    pub request_id: Option<String>,
+    /// Entries without it were posted
+    #[serde(default)]
+    pub outcome: Option<Outcome>,
 }
 
-pub struct Log {
-    state: Vec<LogEntry>,
-    file_path: PathBuf,
+impl LogEntry {
+    pub fn outcome(&self) -> Outcome {
+        self.outcome.unwrap_or(Outcome::Posted)
+    }
 }
 
-pub struct LogIter<'t> {
-    log: &'t Log,
-    ptr: usize,
+/// How long the CSV history is kept around, in memory and on disk
+#[derive(Debug, Default)]
+pub struct Retention {
+    /// Older entries are dropped from memory (but not from the file)
+    pub max_age: Option<TimeDelta>,
+    /// Archive the file once it gets this large (bytes)
+    pub rotate_size: Option<u64>,
+    /// Archive the file when a new month starts
+    pub rotate_monthly: bool,
 }
 
-impl<'t> Iterator for LogIter<'t> {
-    type Item = &'t LogEntry;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.ptr >= self.log.state.len() {
-            None
-        } else {
-            let val = Some(&self.log.state[self.ptr]);
-            self.ptr += 1;
-            val
-        }
-    }
+pub struct Log {
+    state: Vec<LogEntry>,
+    /// Latest outcome of the requests which have been answered, by ID
+    requests: HashMap<String, Outcome>,
+    /// URLs of the results which were posted before request IDs were kept
+    unidentified: HashSet<String>,
+    /// Recent requests of each account
+    rates: UserRates,
+    failed_rates: UserRates,
+    file_path: PathBuf,
+    retention: Retention,
+    /// When the file was last written to (as far as can be told from its entries)
+    last_time: Option<DateTime<Utc>>,
 }
 
 impl Log {
     pub fn new(file: impl AsRef<Path>) -> Result<Self> {
+        Self::with_retention(file, Retention::default())
+    }
+
+    pub fn with_retention(file: impl AsRef<Path>, retention: Retention) -> Result<Self> {
         let mut s = Self {
             file_path: file.as_ref().to_path_buf(),
             state: Vec::new(),
+            requests: HashMap::new(),
+            unidentified: HashSet::new(),
+            rates: UserRates::new(RATE_WINDOW),
+            failed_rates: UserRates::new(RATE_WINDOW),
+            retention,
+            last_time: None,
         };
         s.reload()?;
         Ok(s)
@@ -55,34 +179,122 @@ impl Log {
             .create(true)
             .truncate(false)
             .open(&self.file_path)?;
-        let mut reader = csv::ReaderBuilder::new().has_headers(false).from_reader(f);
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(f);
         let res: Result<Vec<_>> = reader
             .deserialize()
             .map(|r| r.map_err(|e| e.into()))
             .collect();
         self.state = res?;
+        self.last_time = self.state.iter().map(|e| e.time).max();
+        if let Some(max_age) = self.retention.max_age {
+            let start = Utc::now() - max_age;
+            self.state.retain(|e| e.time > start);
+        }
+        self.requests = self
+            .state
+            .iter()
+            .filter_map(|e| Some((e.request_id.clone()?, e.outcome())))
+            .collect();
+        self.unidentified = self
+            .state
+            .iter()
+            .filter(|e| e.request_id.is_none() && e.outcome() == Outcome::Posted)
+            .map(|e| e.url.clone())
+            .collect();
+        self.rates = UserRates::new(RATE_WINDOW);
+        self.failed_rates = UserRates::new(RATE_WINDOW);
+        for entry in &self.state {
+            match entry.outcome() {
+                Outcome::Posted => self.rates.record(&entry.user, entry.time),
+                // the bot being busy isn't the account's doing
+                Outcome::Busy => {}
+                _ => self.failed_rates.record(&entry.user, entry.time),
+            }
+        }
         Ok(())
     }
 
-    pub fn _iter_from_for_user<T: TimeZone>(&self, start: DateTime<T>, user: Option<&str>) -> LogIter {
-        LogIter {
-            log: self,
-            ptr: self
-                .state
-                .iter()
-                .enumerate()
-                .find(|(_n, e)| e.time > start && user.map(|u| u == e.user).unwrap_or(true))
-                .map(|(n, _e)| n)
-                .unwrap_or(self.state.len() + 1)
-                .saturating_sub(1),
+    /// All entries, oldest first
+    pub fn entries(&self) -> &[LogEntry] {
+        &self.state
+    }
+
+    /// Forget the entries which are older than the retention period
+    fn expire(&mut self, now: DateTime<Utc>) {
+        let Some(max_age) = self.retention.max_age else {
+            return;
+        };
+        let start = now - max_age;
+        let requests = &mut self.requests;
+        let unidentified = &mut self.unidentified;
+        self.state.retain(|e| {
+            let keep = e.time > start;
+            if !keep {
+                match &e.request_id {
+                    // unless a later entry replaced it
+                    Some(id) if requests.get(id) == Some(&e.outcome()) => {
+                        requests.remove(id);
+                    }
+                    Some(_) => {}
+                    None => {
+                        unidentified.remove(&e.url);
+                    }
+                }
+            }
+            keep
+        });
+    }
+
+    /// Whether the file should be archived before adding an entry at `now`
+    fn needs_rotation(&self, now: DateTime<Utc>) -> Result<bool> {
+        let new_month = self
+            .last_time
+            .is_some_and(|last| (last.year(), last.month()) != (now.year(), now.month()));
+        if self.retention.rotate_monthly && new_month {
+            return Ok(true);
+        }
+        let Some(max_size) = self.retention.rotate_size else {
+            return Ok(false);
+        };
+        match fs::metadata(&self.file_path) {
+            Ok(meta) => Ok(meta.len() >= max_size),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
         }
     }
 
-    pub fn iter_from_for_user<T: TimeZone>(&self, start: DateTime<T>, user: &str) -> LogIter {
-        self._iter_from_for_user(start, Some(user))
+    /// Compress the file into an archive next to it (e.g. `history.20250201T000000.csv.gz`)
+    /// and start a new one
+    fn rotate(&mut self, now: DateTime<Utc>) -> Result<PathBuf> {
+        let stem = self
+            .file_path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let mut name = format!("{stem}.{}", now.format("%Y%m%dT%H%M%S"));
+        if let Some(ext) = self.file_path.extension() {
+            name = format!("{name}.{}", ext.to_string_lossy());
+        }
+        let archive_path = self.file_path.with_file_name(format!("{name}.gz"));
+
+        let mut encoder = GzEncoder::new(File::create(&archive_path)?, Compression::default());
+        io::copy(&mut File::open(&self.file_path)?, &mut encoder)?;
+        encoder.finish()?;
+        fs::remove_file(&self.file_path)?;
+
+        log::info!("Archived history into {}", archive_path.display());
+        Ok(archive_path)
     }
 
-    pub fn log<T: TimeZone>(&mut self, time: DateTime<T>, user: &str, url: &str) -> Result<()> {
+    /// Add an entry at `now`, which may be later than the time of the record itself
+    fn log_at(&mut self, record: &Record, now: DateTime<Utc>) -> Result<()> {
+        if self.needs_rotation(now)? {
+            self.rotate(now).context("Can't archive the history file")?;
+        }
+
         let f = File::options()
             .append(true)
             .truncate(false)
@@ -91,9 +303,13 @@ impl Log {
 
         let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(f);
         let entry = LogEntry {
-            time: time.to_utc(),
-            user: user.into(),
-            url: url.into(),
+            time: record.time,
+            user: record.user.into(),
+            url: record.url.unwrap_or_default().into(),
+            audio_peak: record.audio.map(|a| a.peak),
+            audio_rms: record.audio.map(|a| a.rms()),
+            request_id: Some(record.request_id.into()),
+            outcome: Some(record.outcome),
         };
 
         // log to disk
@@ -101,8 +317,194 @@ impl Log {
         writer.flush()?;
 
         // log to memory
+        self.requests
+            .insert(record.request_id.into(), record.outcome);
+        match record.outcome {
+            Outcome::Posted => self.rates.record(record.user, entry.time),
+            Outcome::Busy => {}
+            _ => self.failed_rates.record(record.user, entry.time),
+        }
+        self.last_time = self.last_time.max(Some(now));
         self.state.push(entry);
+        self.expire(now);
 
         Ok(())
     }
 }
+
+/// The CSV file only keeps the basics, for compatibility with older versions
+impl HistoryStore for Log {
+    fn has_request(&self, id: &str) -> Result<bool> {
+        Ok(self.requests.contains_key(id))
+    }
+
+    fn has_result(&self, id: &str, url: &str) -> Result<bool> {
+        Ok(self.requests.get(id) == Some(&Outcome::Posted) || self.unidentified.contains(url))
+    }
+
+    fn rates(&self) -> &UserRates {
+        &self.rates
+    }
+
+    fn failed_rates(&self) -> &UserRates {
+        &self.failed_rates
+    }
+
+    fn retention_start(&self) -> Option<DateTime<Utc>> {
+        self.retention.max_age.map(|max_age| Utc::now() - max_age)
+    }
+
+    fn log(&mut self, record: &Record) -> Result<()> {
+        self.log_at(record, Utc::now())
+    }
+}
+
+#[cfg(test)]
+impl<'t> Record<'t> {
+    pub fn posted(request_id: &'t str, user: &'t str, url: &'t str) -> Self {
+        Self {
+            request_id,
+            user,
+            time: Utc::now(),
+            outcome: Outcome::Posted,
+            url: Some(url),
+            requested_at: None,
+            run_time: None,
+            grid_hash: None,
+            rom_hash: None,
+            audio: None,
+            error: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.csv");
+        // entries from older versions don't have all the columns
+        std::fs::write(
+            &path,
+            "2025-01-10T10:00:00Z,someone,https://example.com/1\n\
+             2025-01-10T11:00:00Z,someone,https://example.com/2,0.5,0.1\n",
+        )
+        .unwrap();
+
+        let mut log = Log::new(&path).unwrap();
+        assert!(!log.has_request("42").unwrap());
+        log.log(&Record::posted("42", "someone", "https://example.com/3"))
+            .unwrap();
+        assert!(log.has_request("42").unwrap());
+
+        let log = Log::new(&path).unwrap();
+        assert!(log.has_request("42").unwrap());
+        assert_eq!(log.state.len(), 3);
+        // older results can still be told apart by their URL
+        assert!(log.has_result("1", "https://example.com/2").unwrap());
+        assert!(!log.has_result("1", "https://example.com/4").unwrap());
+    }
+
+    #[test]
+    fn test_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.csv");
+        std::fs::write(
+            &path,
+            "2025-01-10T10:00:00Z,someone,https://example.com/1,,,1\n\
+             2025-01-31T11:00:00Z,someone,https://example.com/2,,,2\n",
+        )
+        .unwrap();
+
+        let retention = || Retention {
+            max_age: Some(TimeDelta::days(7)),
+            rotate_size: None,
+            rotate_monthly: true,
+        };
+        let mut log = Log::with_retention(&path, retention()).unwrap();
+        // way older than a week
+        assert!(log.entries().is_empty());
+        assert!(!log.has_request("2").unwrap());
+
+        let mut record = Record::posted("3", "someone", "https://example.com/3");
+        record.time = "2025-02-01T00:00:00Z".parse().unwrap();
+        log.log_at(&record, record.time).unwrap();
+        record.request_id = "4";
+        record.time += TimeDelta::days(8);
+        log.log_at(&record, record.time).unwrap();
+        assert!(!log.has_request("3").unwrap());
+        assert!(log.has_request("4").unwrap());
+
+        // late entries about January don't start another archive
+        let now = record.time;
+        record.request_id = "5";
+        record.time = "2025-01-31T12:00:00Z".parse().unwrap();
+        log.log_at(&record, now).unwrap();
+
+        // January went into the archive
+        let archives: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|f| f.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|e| e == "gz"))
+            .collect();
+        assert_eq!(archives.len(), 1);
+        let mut archived = String::new();
+        io::Read::read_to_string(
+            &mut flate2::read::GzDecoder::new(File::open(&archives[0]).unwrap()),
+            &mut archived,
+        )
+        .unwrap();
+        assert_eq!(archived.lines().count(), 2);
+
+        let log = Log::new(&path).unwrap();
+        assert_eq!(log.entries().len(), 3);
+    }
+
+    #[test]
+    fn test_outcomes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.csv");
+
+        let mut log = Log::new(&path).unwrap();
+        log.log(&Record::posted("1", "someone", "https://example.com/1"))
+            .unwrap();
+        log.log(&Record {
+            outcome: Outcome::ParseError,
+            url: None,
+            error: Some("Line too long"),
+            ..Record::posted("2", "someone", "")
+        })
+        .unwrap();
+
+        log.log(&Record {
+            outcome: Outcome::Busy,
+            url: None,
+            ..Record::posted("3", "someone", "")
+        })
+        .unwrap();
+        // the reply went through after all
+        log.log(&Record {
+            outcome: Outcome::PostFailed,
+            url: None,
+            ..Record::posted("4", "someone", "")
+        })
+        .unwrap();
+        assert!(!log.has_result("4", "https://example.com/4").unwrap());
+        log.log(&Record::posted("4", "someone", "https://example.com/4"))
+            .unwrap();
+        assert!(log.has_result("4", "https://example.com/4").unwrap());
+
+        let log = Log::new(&path).unwrap();
+        assert!(log.has_request("2").unwrap());
+        assert!(!log.has_result("2", "").unwrap());
+        assert!(log.has_result("4", "https://example.com/4").unwrap());
+        assert_eq!(log.entries()[1].outcome(), Outcome::ParseError);
+        let start = Utc::now() - RATE_WINDOW;
+        assert_eq!(log.rates().count_since("someone", start), 2);
+        // being busy doesn't count
+        assert_eq!(log.failed_rates().count_since("someone", start), 2);
+    }
+}
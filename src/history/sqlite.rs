@@ -0,0 +1,292 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::{HistoryStore, LogEntry, Outcome, Record, RATE_WINDOW};
+use crate::ratelimit::UserRates;
+
+/// Version of the schema below, to be bumped along with any changes to it
+const SCHEMA_VERSION: i32 = 1;
+
+const SCHEMA: &str = "
+CREATE TABLE requests (
+    id INTEGER PRIMARY KEY,
+    request_id TEXT UNIQUE,
+    user TEXT NOT NULL,
+    time TEXT NOT NULL,
+    outcome TEXT NOT NULL,
+    url TEXT,
+    requested_at TEXT,
+    run_time REAL,
+    grid_hash TEXT,
+    audio_peak REAL,
+    audio_rms REAL,
+    error TEXT,
+    rom_hash TEXT
+);
+CREATE INDEX requests_user_time ON requests (user, time);
+";
+
+/// Requests which didn't get a result may still get one later (e.g. if posting it only seemed to fail),
+/// in which case the details of the first attempt are kept
+const ON_CONFLICT: &str = "
+ON CONFLICT (request_id) DO UPDATE SET
+    time = excluded.time,
+    outcome = excluded.outcome,
+    url = excluded.url,
+    requested_at = COALESCE(excluded.requested_at, requested_at),
+    run_time = COALESCE(excluded.run_time, run_time),
+    grid_hash = COALESCE(excluded.grid_hash, grid_hash),
+    audio_peak = COALESCE(excluded.audio_peak, audio_peak),
+    audio_rms = COALESCE(excluded.audio_rms, audio_rms),
+    error = excluded.error,
+    rom_hash = COALESCE(excluded.rom_hash, rom_hash)
+WHERE requests.outcome != 'posted'
+";
+
+/// History kept in an SQLite database, with more details than the CSV file
+pub struct SqliteHistory {
+    conn: Connection,
+    rates: UserRates,
+    failed_rates: UserRates,
+}
+
+impl SqliteHistory {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        let version: i32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        match version {
+            0 => conn.execute_batch(SCHEMA)?,
+            SCHEMA_VERSION => {}
+            _ => bail!("Unsupported history database version {version}"),
+        }
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+
+        // only recent requests matter for rate limiting
+        let mut rates = UserRates::new(RATE_WINDOW);
+        let mut failed_rates = UserRates::new(RATE_WINDOW);
+        {
+            let mut stmt = conn.prepare(
+                "SELECT user, time, outcome FROM requests WHERE time > ?1 ORDER BY time",
+            )?;
+            let rows = stmt.query_map([Utc::now() - RATE_WINDOW], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, DateTime<Utc>>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?;
+            for row in rows {
+                let (user, time, outcome) = row?;
+                if outcome == Outcome::Posted.as_str() {
+                    rates.record(&user, time);
+                } else if outcome != Outcome::Busy.as_str() {
+                    failed_rates.record(&user, time);
+                }
+            }
+        }
+
+        Ok(Self {
+            conn,
+            rates,
+            failed_rates,
+        })
+    }
+
+    fn rates_mut(&mut self, outcome: Outcome) -> Option<&mut UserRates> {
+        match outcome {
+            Outcome::Posted => Some(&mut self.rates),
+            // the bot being busy isn't the account's doing
+            Outcome::Busy => None,
+            _ => Some(&mut self.failed_rates),
+        }
+    }
+
+    /// Copy over the entries of a CSV history file, returning how many there were
+    pub fn import(&mut self, entries: &[LogEntry]) -> Result<usize> {
+        let existing: usize = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM requests", [], |row| row.get(0))?;
+        if existing > 0 {
+            // entries without a request ID can't be told apart, so they'd end up duplicated
+            bail!("The database already has {existing} entries");
+        }
+
+        let tx = self.conn.transaction()?;
+        for entry in entries {
+            tx.execute(
+                &format!(
+                    "INSERT INTO requests (request_id, user, time, outcome, url, audio_peak, audio_rms)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) {ON_CONFLICT}"
+                ),
+                params![
+                    entry.request_id,
+                    entry.user,
+                    entry.time,
+                    entry.outcome().as_str(),
+                    entry.url,
+                    entry.audio_peak,
+                    entry.audio_rms,
+                ],
+            )?;
+        }
+        tx.commit()?;
+
+        for entry in entries {
+            if let Some(rates) = self.rates_mut(entry.outcome()) {
+                rates.record(&entry.user, entry.time);
+            }
+        }
+        Ok(entries.len())
+    }
+}
+
+impl HistoryStore for SqliteHistory {
+    fn has_request(&self, id: &str) -> Result<bool> {
+        Ok(self
+            .conn
+            .query_row("SELECT 1 FROM requests WHERE request_id = ?1", [id], |_| {
+                Ok(())
+            })
+            .optional()?
+            .is_some())
+    }
+
+    fn has_result(&self, id: &str, url: &str) -> Result<bool> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT 1 FROM requests
+                 WHERE (request_id = ?1 OR (request_id IS NULL AND url = ?2)) AND outcome = ?3",
+                [id, url, Outcome::Posted.as_str()],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some())
+    }
+
+    fn rates(&self) -> &UserRates {
+        &self.rates
+    }
+
+    fn failed_rates(&self) -> &UserRates {
+        &self.failed_rates
+    }
+
+    fn log(&mut self, record: &Record) -> Result<()> {
+        self.conn.execute(
+            &format!(
+                "INSERT INTO requests
+                    (request_id, user, time, outcome, url, requested_at, run_time, grid_hash, audio_peak, audio_rms, error, rom_hash)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12) {ON_CONFLICT}"
+            ),
+            params![
+                record.request_id,
+                record.user,
+                record.time,
+                record.outcome.as_str(),
+                record.url,
+                record.requested_at,
+                record.run_time.map(|t| t.as_secs_f64()),
+                record.grid_hash,
+                record.audio.map(|a| a.peak),
+                record.audio.map(|a| a.rms()),
+                record.error,
+                record.rom_hash,
+            ],
+        )?;
+        if let Some(rates) = self.rates_mut(record.outcome) {
+            rates.record(record.user, record.time);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::Log;
+
+    #[test]
+    fn test_sqlite_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let csv_path = dir.path().join("history.csv");
+        let db_path = dir.path().join("history.db");
+
+        let now = Utc::now().to_rfc3339();
+        std::fs::write(
+            &csv_path,
+            format!(
+                "2025-01-10T10:00:00Z,someone,https://example.com/1\n\
+                 {now},someone,https://example.com/2,0.5,0.1,41\n"
+            ),
+        )
+        .unwrap();
+
+        let mut history = SqliteHistory::open(&db_path).unwrap();
+        let csv = Log::new(&csv_path).unwrap();
+        assert_eq!(history.import(csv.entries()).unwrap(), 2);
+        assert!(history.import(csv.entries()).is_err());
+
+        history
+            .log(&Record::posted("42", "someone", "https://example.com/3"))
+            .unwrap();
+        history
+            .log(&Record {
+                outcome: Outcome::RunFailed,
+                url: None,
+                error: Some("Timed out"),
+                rom_hash: Some("abc"),
+                ..Record::posted("43", "someone", "")
+            })
+            .unwrap();
+
+        let mut history = SqliteHistory::open(&db_path).unwrap();
+        assert!(history.has_request("41").unwrap());
+        assert!(history.has_request("42").unwrap());
+        assert!(history.has_request("43").unwrap());
+        assert!(!history.has_request("44").unwrap());
+        // imported without an ID
+        assert!(history.has_result("", "https://example.com/1").unwrap());
+        let rom_hash: String = history
+            .conn
+            .query_row(
+                "SELECT rom_hash FROM requests WHERE request_id = '43'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(rom_hash, "abc");
+        assert!(!history.has_result("43", "https://example.com/4").unwrap());
+        // the oldest one is out of the window
+        assert_eq!(
+            history
+                .rates()
+                .count_since("someone", Utc::now() - RATE_WINDOW),
+            2
+        );
+        assert_eq!(
+            history
+                .failed_rates()
+                .count_since("someone", Utc::now() - RATE_WINDOW),
+            1
+        );
+
+        // the reply went out after all, which replaces the failure
+        history
+            .log(&Record::posted("43", "someone", "https://example.com/4"))
+            .unwrap();
+        assert!(history.has_result("43", "https://example.com/4").unwrap());
+        let (outcome, rom_hash): (String, String) = history
+            .conn
+            .query_row(
+                "SELECT outcome, rom_hash FROM requests WHERE request_id = '43'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((outcome.as_str(), rom_hash.as_str()), ("posted", "abc"));
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// How long finished jobs are remembered
+const RETENTION: TimeDelta = TimeDelta::days(1);
+
+/// Where a job is at
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Posted { url: String },
+    Failed { error: String },
+}
+
+impl JobStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobStatus::Posted { .. } | JobStatus::Failed { .. })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Record<T> {
+    /// ID of the post which requested the job
+    id: String,
+    status: JobStatus,
+    /// When the job was accepted
+    accepted: DateTime<Utc>,
+    updated: DateTime<Utc>,
+    job: T,
+}
+
+/// Keeps track (on disk) of accepted jobs, so that none are lost if the bot is restarted
+pub struct JobStore<T> {
+    file_path: PathBuf,
+    records: Vec<Record<T>>,
+}
+
+impl<T: Serialize + DeserializeOwned + Clone> JobStore<T> {
+    pub fn new(file: impl AsRef<Path>) -> Result<Self> {
+        let file_path = file.as_ref().to_path_buf();
+        let records = match fs::read_to_string(&file_path) {
+            Ok(content) => serde_json::from_str(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { file_path, records })
+    }
+
+    fn save(&mut self) -> Result<()> {
+        let now = Utc::now();
+        self.records
+            .retain(|r| !r.status.is_finished() || now - r.updated < RETENTION);
+
+        // write to a temporary file first, so that we never end up with a half-written file
+        // (named after the whole file name, since the cursor lives next to it with the same stem)
+        let mut tmp_path = self.file_path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(&self.records)?)?;
+        fs::rename(&tmp_path, &self.file_path)?;
+        Ok(())
+    }
+
+    pub fn status(&self, id: &str) -> Option<&JobStatus> {
+        self.records.iter().find(|r| r.id == id).map(|r| &r.status)
+    }
+
+    /// Record a new job, which is queued
+    pub fn insert(&mut self, id: &str, job: T) -> Result<()> {
+        self.records.retain(|r| r.id != id);
+        let now = Utc::now();
+        self.records.push(Record {
+            id: id.into(),
+            status: JobStatus::Queued,
+            accepted: now,
+            updated: now,
+            job,
+        });
+        self.save()
+    }
+
+    pub fn set_status(&mut self, id: &str, status: JobStatus) -> Result<()> {
+        let record = self
+            .records
+            .iter_mut()
+            .find(|r| r.id == id)
+            .ok_or_else(|| anyhow!("Unknown job {id}"))?;
+        record.status = status;
+        record.updated = Utc::now();
+        self.save()
+    }
+
+    pub fn remove(&mut self, id: &str) -> Result<()> {
+        self.records.retain(|r| r.id != id);
+        self.save()
+    }
+
+    /// When the unfinished jobs which match `filter` were accepted
+    pub fn accepted_unfinished(&self, filter: impl Fn(&T) -> bool) -> Vec<DateTime<Utc>> {
+        self.records
+            .iter()
+            .filter(|r| !r.status.is_finished() && filter(&r.job))
+            .map(|r| r.accepted)
+            .collect()
+    }
+
+    /// Jobs which were queued or running, in the order they were accepted
+    pub fn unfinished(&self) -> Vec<(String, JobStatus, T)> {
+        self.records
+            .iter()
+            .filter(|r| !r.status.is_finished())
+            .map(|r| (r.id.clone(), r.status.clone(), r.job.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("jobs.json");
+
+        let mut store = JobStore::new(&path).unwrap();
+        store.insert("1", "first".to_string()).unwrap();
+        store.insert("2", "second".to_string()).unwrap();
+        store.insert("3", "third".to_string()).unwrap();
+        store.set_status("1", JobStatus::Running).unwrap();
+        store
+            .set_status(
+                "2",
+                JobStatus::Posted {
+                    url: "https://example.com/2".into(),
+                },
+            )
+            .unwrap();
+        assert!(store.set_status("4", JobStatus::Running).is_err());
+
+        // after a restart
+        let store: JobStore<String> = JobStore::new(&path).unwrap();
+        assert_eq!(
+            store.unfinished(),
+            vec![
+                ("1".into(), JobStatus::Running, "first".into()),
+                ("3".into(), JobStatus::Queued, "third".into()),
+            ]
+        );
+        assert!(store.status("2").is_some_and(JobStatus::is_finished));
+        assert_eq!(store.accepted_unfinished(|job| job != "third").len(), 1);
+    }
+}
@@ -1,76 +1,277 @@
 use std::{
     fs::{self, File},
+    future::Future,
     io::{stdin, Read},
-    os::unix::fs::MetadataExt,
-    path::Path,
-    time::Duration,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Result};
+use audio::{AudioStats, MixConfig};
 use chrono::prelude::*;
 use clap::Parser;
-use cli::{RunArgs, SubCommands};
+use cli::{HistoryFormat, RunArgs, SubCommands, WhenBusy};
+use encoding::{EncodingLimits, EncodingParams, OutputFormat, Thumbnail, ThumbnailPick};
+use serde::{Deserialize, Serialize};
 use tempfile::TempDir;
-use tokio::time;
+use tokio::{
+    signal::{
+        self,
+        unix::{Signal, SignalKind},
+    },
+    sync::watch,
+    task::JoinSet,
+    time,
+};
 
+mod access;
+mod audio;
+mod cache;
 mod cli;
+mod cursor;
 mod encoding;
 mod history;
+mod jobs;
 mod mastodon;
 mod parser;
+mod queue;
+mod ratelimit;
+mod retry;
+mod templates;
 mod vm;
 
-use history::Log;
-use mastodon::Client;
-use parser::{parse_html, parse_orca_code, ParseConfig};
+use access::AccessPolicy;
+use cache::{sha256_hex, ResultCache};
+use cursor::Cursor;
+use history::{HistoryStore, Log, Outcome, Record, Retention, SqliteHistory};
+use jobs::{JobStatus, JobStore};
+use mastodon::{
+    Client, MediaError, Mention, MentionWatcher, PostedResult, VisibilityPolicy,
+    MAX_DESCRIPTION_LENGTH,
+};
+use parser::{parse_html, parse_orca_code, OrcaSource, ParseConfig};
+use queue::{JobQueue, QueueError};
+use ratelimit::SlidingWindow;
+use retry::RetryPolicy;
+use templates::{Message, Rendered, Templates};
+use vm::RunOutput;
+
+/// Longest we'll wait between retries of an API call
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// How often the block and mute lists of the bot account are fetched again
+const BLOCKS_REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// How often old entries are dropped from the cache, even when nothing new is stored
+const CACHE_EVICT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Exit code when jobs had to be interrupted (`EX_TEMPFAIL`)
+const EXIT_INTERRUPTED: i32 = 75;
+
+/// Number of frames each job is run for
+const NUM_FRAMES: usize = 600;
+
+/// Settings which apply to all jobs
+struct JobConfig {
+    rom: PathBuf,
+    native: bool,
+    args: Vec<String>,
+    mix: MixConfig,
+    limits: EncodingLimits,
+    /// Produce a GIF rather than a video when the program is silent
+    gif_if_silent: bool,
+    /// Directory where each audio channel should be written to as a WAV file
+    stems_dir: Option<PathBuf>,
+    /// How to pick the frame used as a thumbnail (if any)
+    thumbnail: Option<ThumbnailPick>,
+    /// Directory where temporary files are created (the system's default if `None`)
+    work_dir: Option<PathBuf>,
+}
+
+/// Result of a simulation and video encoding job
+struct JobOutput {
+    /// Holds the temporary directory, which is deleted when dropped
+    _dir: TempDir,
+    file: PathBuf,
+    encoding: EncodingParams,
+    audio: AudioStats,
+    thumbnail: Option<Thumbnail>,
+}
 
-const GREETING: &str = "Hey there 🤖 BLEEP BLOP 🎵 !";
+/// Temporary directory for the files of a job
+fn job_dir(config: &JobConfig) -> Result<TempDir> {
+    Ok(match &config.work_dir {
+        Some(dir) => tempfile::tempdir_in(dir)?,
+        None => tempfile::tempdir()?,
+    })
+}
 
 /// Run a simulation and video encoding job
 fn run_job<'t>(
-    rom: impl AsRef<Path>,
+    config: &'t JobConfig,
     input: impl Iterator<Item = &'t [char]>,
-    native: bool,
-    args: &'t Vec<String>,
-) -> Result<TempDir> {
-    let screen_dir = tempfile::tempdir()?;
+) -> Result<JobOutput> {
+    let screen_dir = job_dir(config)?;
     let audio_file = screen_dir.as_ref().join("audio.pcm");
-    let out_file = screen_dir.as_ref().join("out.mp4");
 
-    let vm = vm::VMWrapper::new(&screen_dir, &audio_file, args, native);
-    let (width, height) = vm
-        .run(rom, input, 600)
+    let vm = vm::VMWrapper::new(
+        &screen_dir,
+        &audio_file,
+        &config.args,
+        &config.mix,
+        config.native,
+        config.stems_dir.as_deref(),
+    );
+    let RunOutput { size, audio } = vm
+        .run(&config.rom, input, NUM_FRAMES)
         .context("Couldn't run the VM properly")?;
 
-    log::debug!("Generating {width}x{height} video...");
+    let format = if config.gif_if_silent && audio.is_silent() {
+        OutputFormat::Gif
+    } else {
+        OutputFormat::Video
+    };
+    let out_file = screen_dir
+        .as_ref()
+        .join(format!("out.{}", format.extension()));
+
+    log::debug!(
+        "Generating {}x{} {format:?} (audio: {audio})...",
+        size.0,
+        size.1
+    );
+
+    let encoding = encoding::encode_within_limits(
+        &screen_dir,
+        &audio_file,
+        &out_file,
+        EncodingParams::new(format, size, NUM_FRAMES, config.mix.loudness_target),
+        &config.limits,
+    )
+    .context("Can't encode video")?;
+
+    log::debug!("Done! ({encoding})");
+
+    // not having a thumbnail isn't the end of the world
+    let thumbnail = config.thumbnail.and_then(|pick| {
+        encoding::render_thumbnail(
+            &screen_dir,
+            &encoding,
+            pick,
+            screen_dir.as_ref().join("thumbnail.png"),
+        )
+        .inspect_err(|e| log::warn!("Can't generate thumbnail: {e}"))
+        .ok()
+    });
+
+    Ok(JobOutput {
+        _dir: screen_dir,
+        file: out_file,
+        encoding,
+        audio,
+        thumbnail,
+    })
+}
+
+/// What is kept in the cache about the result of a job, besides the files
+#[derive(Serialize, Deserialize)]
+struct CachedOutput {
+    encoding: EncodingParams,
+    audio: AudioStats,
+    thumbnail_focus: Option<(f32, f32)>,
+}
+
+/// Everything besides the code which has an effect on the result of a job
+fn job_fingerprint(config: &JobConfig, rom_hash: &str) -> String {
+    format!(
+        "rom={rom_hash} args={:?} mix={:?} limits={:?} gif={} thumbnail={:?} frames={NUM_FRAMES}",
+        config.args, config.mix, config.limits, config.gif_if_silent, config.thumbnail,
+    )
+}
+
+/// Run a job, unless the same code was already run with the same settings
+fn run_job_cached(
+    config: &JobConfig,
+    cache: Option<&ResultCache>,
+    source: &OrcaSource,
+) -> Result<JobOutput> {
+    let Some(cache) = cache else {
+        return run_job(config, source.iter_lines());
+    };
+    let key = cache.key(&source.to_string());
+
+    let dir = job_dir(config)?;
+    match cache.get::<CachedOutput>(&key, dir.path()) {
+        Ok(Some(cached)) => {
+            log::info!("Found result in the cache ({key})");
+            return Ok(JobOutput {
+                file: dir
+                    .path()
+                    .join(format!("out.{}", cached.encoding.format.extension())),
+                encoding: cached.encoding,
+                audio: cached.audio,
+                thumbnail: cached.thumbnail_focus.map(|focus| Thumbnail {
+                    path: dir.path().join("thumbnail.png"),
+                    focus,
+                }),
+                _dir: dir,
+            });
+        }
+        Ok(None) => {}
+        // not the end of the world, the job can still be run
+        Err(e) => log::warn!("Can't read from the cache: {e:#}"),
+    }
 
-    encoding::encode(&screen_dir, (width, height), &audio_file, &out_file)
-        .context("Can't encode video")?;
+    let output = run_job(config, source.iter_lines())?;
 
-    log::debug!("Done!");
+    let mut files = vec![output.file.as_path()];
+    files.extend(output.thumbnail.as_ref().map(|t| t.path.as_path()));
+    let cached = CachedOutput {
+        encoding: output.encoding.clone(),
+        audio: output.audio,
+        thumbnail_focus: output.thumbnail.as_ref().map(|t| t.focus),
+    };
+    if let Err(e) = cache.put(&key, &files, &cached) {
+        log::warn!("Can't store the result in the cache: {e:#}");
+    }
 
-    Ok(screen_dir)
+    Ok(output)
 }
 
 /// Check that the aaccount rate limits haven't been crossed
+///
+/// `pending` holds the times at which the account's queued and running jobs were accepted,
+/// since those aren't in the history yet.
 fn user_rate_is_ok(
-    history: &Log,
+    history: &dyn HistoryStore,
+    pending: &[DateTime<Utc>],
     username: &str,
     min_wait_interval: Duration,
     max_requests_hour: usize,
+    count_failures: bool,
 ) -> bool {
-    if history
-        .iter_from_for_user(Local::now() - min_wait_interval, username)
-        .next()
-        .is_some()
-    {
+    let now = Utc::now();
+    let hour_ago = now - Duration::from_secs(60 * 60);
+    let mut rates = vec![history.rates()];
+    if count_failures {
+        rates.push(history.failed_rates());
+    }
+    let last = rates
+        .iter()
+        .filter_map(|r| r.last(username))
+        .chain(pending.iter().copied())
+        .max();
+    let count = rates
+        .iter()
+        .map(|r| r.count_since(username, hour_ago))
+        .sum::<usize>()
+        + pending.iter().filter(|t| **t > hour_ago).count();
+
+    if last.is_some_and(|last| last > now - min_wait_interval) {
         // there is at least one history entry from this account in the last N seconds
         false
-    } else if history
-        .iter_from_for_user(Local::now() - Duration::from_secs(60 * 60), username)
-        .count()
-        >= max_requests_hour
-    {
+    } else if count >= max_requests_hour {
         // there are more then N requests from this account in the last hours
         false
     } else {
@@ -78,119 +279,760 @@ fn user_rate_is_ok(
     }
 }
 
+/// Describe the result for those who can't see it, including the code if it fits
+fn alt_text(source: &OrcaSource, output: &JobOutput) -> String {
+    let (width, height) = source.size();
+    let description = format!(
+        "{} of an Orca program on a {width}x{height} grid, running for {:.1}s{}.",
+        match output.encoding.format {
+            OutputFormat::Video => "Video",
+            OutputFormat::Gif => "Animation",
+        },
+        output.encoding.duration.as_secs_f64(),
+        if output.audio.is_silent() {
+            ", without sound"
+        } else {
+            ", with sound"
+        }
+    );
+
+    let with_code = format!("{description}\n\nSource code:\n{source}");
+    if with_code.chars().count() <= MAX_DESCRIPTION_LENGTH {
+        with_code
+    } else {
+        format!("{description} The source code is too long to be included here.")
+    }
+}
+
+/// Text of the post with the result, including any warnings
+fn result_text(
+    templates: &Templates,
+    mention: &Mention,
+    source: &OrcaSource,
+    output: &JobOutput,
+) -> Rendered {
+    let language = mention.language.as_deref();
+    let user = &mention.account.acct;
+    let duration = format!("{:.1}s", output.encoding.duration.as_secs_f64());
+
+    let mut warnings = Vec::new();
+    if output.encoding.format == OutputFormat::Gif {
+        warnings.push(templates.render(Message::Silent, language, user, &[]).text);
+    }
+    if output.encoding.duration
+        < Duration::from_secs_f64(NUM_FRAMES as f64 / encoding::FRAME_RATE as f64)
+    {
+        let values = [("duration", duration.as_str())];
+        warnings.push(
+            templates
+                .render(Message::Trimmed, language, user, &values)
+                .text,
+        );
+    }
+
+    let (width, height) = source.size();
+    templates.render(
+        Message::Result,
+        language,
+        user,
+        &[
+            ("duration", &duration),
+            ("grid_size", &format!("{width}x{height}")),
+            ("warnings", &warnings.join("\n\n")),
+        ],
+    )
+}
+
+/// A request which passed all checks and is waiting to be run
+#[derive(Clone, Serialize, Deserialize)]
+struct Job {
+    mention: Mention,
+    source: OrcaSource,
+}
+
+/// State shared by the main loop and the workers
+struct Bot {
+    args: RunArgs,
+    client: Client,
+    history: Mutex<Box<dyn HistoryStore>>,
+    jobs: Mutex<JobStore<Job>>,
+    templates: Templates,
+    job_config: JobConfig,
+    cache: Option<ResultCache>,
+    /// Hash of the ROM, to tell which version produced each result
+    rom_hash: String,
+    /// Requests accepted in the last hour, from all accounts
+    budget: Mutex<SlidingWindow>,
+}
+
+impl Bot {
+    /// Keep track of the progress of a job (`None` means it can be forgotten)
+    fn update_job(&self, id: &str, status: Option<JobStatus>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let res = match status {
+            Some(status) => jobs.set_status(id, status),
+            None => jobs.remove(id),
+        };
+        if let Err(e) = res {
+            log::error!("Can't update job for post {id}: {e:#}");
+        }
+    }
+
+    /// Keep a record of a request which didn't end up with a result being posted
+    fn record_failure(&self, mention: &Mention, outcome: Outcome, error: Option<&str>) {
+        if self.args.do_not_post {
+            return;
+        }
+        let res = self.history.lock().unwrap().log(&Record {
+            request_id: &mention.status_id,
+            user: &mention.account.acct,
+            time: Utc::now(),
+            outcome,
+            url: None,
+            requested_at: mention.created_at,
+            run_time: None,
+            grid_hash: None,
+            rom_hash: Some(&self.rom_hash),
+            audio: None,
+            error,
+        });
+        if let Err(e) = res {
+            log::error!(
+                "Can't record post {} in the history: {e:#}",
+                mention.status_id
+            );
+        }
+    }
+
+    /// Reply to a mention with one of the template messages
+    async fn reply(
+        &self,
+        mention: &Mention,
+        message: Message,
+        values: &[(&str, &str)],
+    ) -> Result<()> {
+        if self.args.do_not_post {
+            return Ok(());
+        }
+        let message = self.templates.render(
+            message,
+            mention.language.as_deref(),
+            &mention.account.acct,
+            values,
+        );
+        self.client
+            .message_account(mention, &message.text, &message.language)
+            .await
+    }
+}
+
+/// Whether there's no room for more requests, either in the queue or in the hourly budget
+fn is_busy(bot: &Bot, queue: &JobQueue<Job>) -> bool {
+    queue.is_full() || !bot.budget.lock().unwrap().has_room(Instant::now())
+}
+
+/// Wait until more requests can be accepted
+async fn wait_until_not_busy(bot: &Bot, queue: &JobQueue<Job>) {
+    loop {
+        queue.wait_for_space().await;
+        let now = Instant::now();
+        let next_free = bot.budget.lock().unwrap().next_free(now);
+        if next_free == now {
+            return;
+        }
+        log::info!(
+            "Hourly budget spent, waiting {:?} before taking more requests",
+            next_free - now
+        );
+        time::sleep_until(next_free.into()).await;
+    }
+}
+
+/// Check a mention and queue it up if it's a valid request
+async fn accept_mention(
+    bot: &Bot,
+    parse_config: &ParseConfig<'_>,
+    queue: &JobQueue<Job>,
+    mention: &Mention,
+) -> Result<()> {
+    let Mention {
+        status_id, account, ..
+    } = mention;
+    log::info!(
+        "Processing post {status_id} from {} ({}, {}){}",
+        account.display_name,
+        account.acct,
+        account.url,
+        if account.bot { " [bot]" } else { "" }
+    );
+    log::debug!(
+        "Post {status_id} ({}): {:?}, language {:?}, CW {:?}, {} attachment(s), in reply to {:?} (account {:?})",
+        mention.status_url.as_deref().unwrap_or("no URL"),
+        mention.visibility,
+        mention.language,
+        mention.spoiler_text,
+        mention.attachments.len(),
+        mention.in_reply_to_id,
+        mention.in_reply_to_account_id,
+    );
+
+    // the notification may have come back after a restart
+    if bot.history.lock().unwrap().has_request(status_id)? {
+        log::info!("Skipped {status_id}: already answered");
+        return Ok(());
+    }
+    let known = bot.jobs.lock().unwrap().status(status_id).cloned();
+    if let Some(status) = known {
+        log::info!("Skipped {status_id}: already accepted ({status:?})");
+        return Ok(());
+    }
+
+    // look for valid HTML
+    let parsed = match parse_html(&mention.content, parse_config) {
+        Err(parser::ParseError::NoPreludeFound) => {
+            // Skip it
+            log::debug!("Skipped {status_id}: doesn't include prelude");
+            return Ok(());
+        }
+        Err(parser::ParseError::Io(e)) => {
+            log::error!("Problem parsing content: {e}");
+            return Ok(());
+        }
+        parsed => parsed,
+    };
+
+    // then let's check that the account is not hammering us, even with broken code
+    let pending = bot
+        .jobs
+        .lock()
+        .unwrap()
+        .accepted_unfinished(|job| job.mention.account.acct == account.acct);
+    let rate_ok = user_rate_is_ok(
+        bot.history.lock().unwrap().as_ref(),
+        &pending,
+        &account.acct,
+        Duration::from_secs(bot.args.min_wait_interval as u64),
+        bot.args.max_requests_hour,
+        bot.args.rate_limit_failures,
+    );
+    if !rate_ok {
+        log::warn!("Request from {} ignored due to rate limit", account.acct);
+        bot.record_failure(mention, Outcome::RateLimited, None);
+        return bot.reply(mention, Message::RateLimited, &[]).await;
+    }
+
+    let source = match parsed {
+        Ok(source) => source,
+        Err(e) => {
+            log::warn!("Ignored {status_id}: {e}");
+            bot.record_failure(mention, Outcome::ParseError, Some(&e.to_string()));
+            return bot
+                .reply(mention, Message::ParseError, &[("error", &e.to_string())])
+                .await;
+        }
+    };
+    log::debug!("HTML OK");
+
+    // only happens with `--when-busy=reply`, otherwise the mention would have been deferred
+    if is_busy(bot, queue) {
+        log::warn!("Request from {} turned down: too busy", account.acct);
+        bot.record_failure(mention, Outcome::Busy, None);
+        return bot.reply(mention, Message::Busy, &[]).await;
+    }
+
+    let job = Job {
+        mention: mention.clone(),
+        source,
+    };
+    // keep a record first, so that the job survives a restart
+    bot.jobs.lock().unwrap().insert(status_id, job.clone())?;
+
+    if let Err(e) = queue.push(&account.acct, job) {
+        bot.jobs.lock().unwrap().remove(status_id)?;
+        log::warn!("Request from {} ignored: {e}", account.acct);
+        let (message, outcome) = match e {
+            QueueError::Full => (Message::Busy, Outcome::Busy),
+            QueueError::UserLimit => (Message::RateLimited, Outcome::RateLimited),
+        };
+        bot.record_failure(mention, outcome, Some(&e.to_string()));
+        return bot.reply(mention, message, &[]).await;
+    }
+    bot.budget.lock().unwrap().record(Instant::now());
+    log::info!("Queued post {status_id}");
+    Ok(())
+}
+
+/// Run a queued job and post the result, returning its URL (if it was actually posted)
+async fn process_job(bot: &Arc<Bot>, job: Job) -> Result<Option<String>> {
+    let Job { mention, source } = job;
+    let status_id = &mention.status_id;
+
+    // the VM and the encoder would otherwise hold up everything else
+    let started = Instant::now();
+    let (source, output) = tokio::task::spawn_blocking({
+        let bot = bot.clone();
+        move || {
+            let output = run_job_cached(&bot.job_config, bot.cache.as_ref(), &source);
+            (source, output)
+        }
+    })
+    .await?;
+    let run_time = started.elapsed();
+
+    let output = match output {
+        Ok(output) => output,
+        Err(e) => {
+            bot.record_failure(&mention, Outcome::RunFailed, Some(&format!("{e:#}")));
+            return Err(e.context("Failed to run job"));
+        }
+    };
+
+    // this means the encoding went well, let's log the final parameters and get to posting it
+    log::info!(
+        "Encoded video: {} (audio: {})",
+        output.encoding,
+        output.audio
+    );
+    if bot.args.do_not_post {
+        log::info!("All done! (wink wink!)");
+        return Ok(None);
+    }
+
+    log::info!("Posting to mastodon, replying to {status_id}");
+    let text = result_text(&bot.templates, &mention, &source, &output);
+    let res = bot
+        .client
+        .post_result(
+            &mention,
+            &output.file,
+            &alt_text(&source, &output),
+            &text.text,
+            &text.language,
+            output.thumbnail.as_ref(),
+        )
+        .await;
+
+    match res {
+        Ok(url) => {
+            log::info!("All done! {url}");
+            let grid_hash = sha256_hex(source.to_string().as_bytes());
+            bot.history.lock().unwrap().log(&Record {
+                request_id: status_id,
+                user: &mention.account.acct,
+                time: Utc::now(),
+                outcome: Outcome::Posted,
+                url: Some(&url),
+                requested_at: mention.created_at,
+                run_time: Some(run_time),
+                grid_hash: Some(&grid_hash),
+                rom_hash: Some(&bot.rom_hash),
+                audio: Some(&output.audio),
+                error: None,
+            })?;
+            Ok(Some(url))
+        }
+        Err(e) if e.downcast_ref::<MediaError>().is_some() => {
+            bot.record_failure(&mention, Outcome::MediaFailed, Some(&format!("{e:#}")));
+            // don't leave the user hanging, at least tell them what happened
+            bot.reply(&mention, Message::MediaFailed, &[]).await?;
+            Err(e)
+        }
+        Err(e) => {
+            bot.record_failure(&mention, Outcome::PostFailed, Some(&format!("{e:#}")));
+            Err(e)
+        }
+    }
+}
+
+/// Make sure that results which were posted right before a crash are on record
+fn reconcile(bot: &Bot, results: &[PostedResult]) -> Result<()> {
+    let retention_start = bot.history.lock().unwrap().retention_start();
+    for result in results.iter().rev() {
+        // those may well be on record, just not in memory anymore
+        if retention_start.is_some_and(|start| result.time <= start) {
+            continue;
+        }
+        if bot
+            .history
+            .lock()
+            .unwrap()
+            .has_result(&result.request_id, &result.url)?
+        {
+            continue;
+        }
+        log::warn!(
+            "Reply to {} wasn't on record, adding it: {}",
+            result.request_id,
+            result.url
+        );
+        bot.history.lock().unwrap().log(&Record {
+            request_id: &result.request_id,
+            user: &result.user,
+            time: result.time,
+            outcome: Outcome::Posted,
+            url: Some(&result.url),
+            requested_at: None,
+            run_time: None,
+            grid_hash: None,
+            rom_hash: None,
+            audio: None,
+            error: None,
+        })?;
+
+        let unfinished = bot
+            .jobs
+            .lock()
+            .unwrap()
+            .status(&result.request_id)
+            .is_some_and(|s| !s.is_finished());
+        if unfinished {
+            bot.update_job(
+                &result.request_id,
+                Some(JobStatus::Posted {
+                    url: result.url.clone(),
+                }),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Take jobs from the queue, one at a time
+async fn run_worker(bot: Arc<Bot>, queue: Arc<JobQueue<Job>>, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        let job = tokio::select! {
+            job = queue.pop() => job,
+            // whatever is left in the queue will be picked up after a restart
+            _ = shutdown.wait_for(|stop| *stop) => return,
+        };
+        let status_id = job.mention.status_id.clone();
+        bot.update_job(&status_id, Some(JobStatus::Running));
+
+        let status = match process_job(&bot, job).await {
+            Ok(Some(url)) => Some(JobStatus::Posted { url }),
+            // nothing was posted, no need to remember it
+            Ok(None) => None,
+            Err(e) => {
+                log::error!("Failed to process post {status_id}: {e:#}");
+                Some(JobStatus::Failed {
+                    error: format!("{e:#}"),
+                })
+            }
+        };
+        bot.update_job(&status_id, status);
+    }
+}
+
+/// Wait for a request to stop, either through Ctrl+C or `SIGTERM` (e.g. `docker stop`)
+async fn shutdown_signal(mut terminate: Signal) -> &'static str {
+    tokio::select! {
+        _ = signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+/// Wait for `fut` to complete, unless the bot is asked to stop first (then `None` is returned)
+async fn until_shutdown<F: Future>(
+    shutdown: &mut watch::Receiver<bool>,
+    fut: F,
+) -> Option<F::Output> {
+    tokio::select! {
+        res = fut => Some(res),
+        _ = shutdown.wait_for(|stop| *stop) => None,
+    }
+}
+
 async fn run_cmd(args: RunArgs) -> Result<()> {
-    let mut history = Log::new(args.history_file)?;
+    let history_file = args.history_file();
+    let mut cursor = Cursor::new(
+        args.cursor_file
+            .clone()
+            .unwrap_or_else(|| history_file.with_extension("cursor")),
+    )?;
+    let (shutdown_tx, mut shutdown) = watch::channel(false);
+    let terminate = signal::unix::signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        let signal = shutdown_signal(terminate).await;
+        log::info!("Got {signal}, shutting down");
+        let _ = shutdown_tx.send(true);
+    });
+
+    let history: Box<dyn HistoryStore> = match args.history_format {
+        HistoryFormat::Csv => {
+            let retention = Retention {
+                max_age: args
+                    .history_max_age
+                    .map(|days| chrono::TimeDelta::days(days as i64)),
+                rotate_size: args.history_rotate_size.map(|mb| mb * 1024 * 1024),
+                rotate_monthly: args.history_rotate_monthly,
+            };
+            Box::new(Log::with_retention(&history_file, retention)?)
+        }
+        HistoryFormat::Sqlite => {
+            if args.history_max_age.is_some()
+                || args.history_rotate_size.is_some()
+                || args.history_rotate_monthly
+            {
+                log::warn!("History retention and rotation only apply to CSV files");
+            }
+            Box::new(SqliteHistory::open(&history_file)?)
+        }
+    };
+    let jobs = JobStore::new(
+        args.jobs_file
+            .clone()
+            .unwrap_or_else(|| history_file.with_extension("jobs")),
+    )?;
+    let templates = Templates::load(args.templates_file.as_deref())?;
 
     log::info!("orca-bot has started! 🎛️ 🤖");
 
+    // all temporary files go here, so that whatever interrupted jobs left behind is cleaned up
+    // on the next start
+    let work_dir = history_file.with_extension("work");
+    if work_dir.exists() {
+        log::info!("Cleaning up files of interrupted jobs");
+        fs::remove_dir_all(&work_dir)?;
+    }
+    fs::create_dir_all(&work_dir)?;
+
+    let job_config = JobConfig {
+        rom: args.rom.clone(),
+        native: args.native,
+        args: args.args.clone(),
+        mix: args.mix.mix_config(),
+        limits: EncodingLimits {
+            max_bytes: Some(args.max_video_size * 1024),
+            max_duration: args.max_video_duration.map(Duration::from_secs),
+        },
+        gif_if_silent: true,
+        stems_dir: None,
+        thumbnail: Some(
+            args.thumbnail_time
+                .map(|t| ThumbnailPick::At(Duration::from_secs_f64(t)))
+                .unwrap_or(ThumbnailPick::Busiest),
+        ),
+        work_dir: Some(work_dir.clone()),
+    };
+
+    let rom =
+        fs::read(&args.rom).with_context(|| format!("Can't read ROM {}", args.rom.display()))?;
+    let rom_hash = sha256_hex(&rom);
+    let cache = match &args.cache_dir {
+        Some(dir) => Some(ResultCache::new(
+            dir,
+            &job_fingerprint(&job_config, &rom_hash),
+            args.cache_max_size * 1024 * 1024,
+            Duration::from_secs(args.cache_max_age * 24 * 60 * 60),
+        )?),
+        None => None,
+    };
+
+    let client = Client::new(
+        args.mastodon_instance_url.clone(),
+        args.mastodon_access_token.clone(),
+        args.server_type.into(),
+        VisibilityPolicy {
+            max_reply: args.max_visibility.into(),
+            max_message: args.max_message_visibility.into(),
+        },
+        RetryPolicy {
+            max_retries: args.api_max_retries,
+            initial_delay: Duration::from_secs(args.api_retry_delay),
+            max_delay: MAX_RETRY_DELAY,
+        },
+        Duration::from_secs(args.media_timeout),
+    )
+    .await?;
+
+    let local_domain = reqwest::Url::parse(&args.mastodon_instance_url)?
+        .host_str()
+        .context("The instance URL has no host")?
+        .to_string();
+    let mut access = AccessPolicy::new(
+        &local_domain,
+        args.allow_bots,
+        args.allow_list.as_deref(),
+        args.deny_list.as_deref(),
+    )?;
+    let mut blocks_due = Instant::now();
+    let mut evict_due = Instant::now();
+    let budget = SlidingWindow::new(
+        Duration::from_secs(60 * 60),
+        args.max_jobs_hour.map_or(usize::MAX, |n| n as usize),
+    );
+
+    let bot = Arc::new(Bot {
+        args,
+        client,
+        history: Mutex::new(history),
+        jobs: Mutex::new(jobs),
+        templates,
+        job_config,
+        cache,
+        rom_hash,
+        budget: Mutex::new(budget),
+    });
+    let args = &bot.args;
+
     let parse_config = ParseConfig {
         tag: &args.run_tag,
         max_line_length: args.max_line_length,
         max_num_lines: args.max_num_lines,
     };
 
-    let client = Client::new(args.mastodon_instance_url, args.mastodon_access_token)?;
+    let queue = Arc::new(JobQueue::new(args.queue_size, args.max_queued_per_user));
 
-    loop {
-        for (notif_id, post_id, (username, url), content) in client.get_notifications().await? {
-            log::info!("Processing post {post_id} from {username} ({url})");
-
-            // look for valid HTML
-            match parse_html(&content, &parse_config) {
-                Ok(source) => {
-                    log::debug!("HTML OK");
-
-                    // first of all, let's check that the account is not hammering us
-                    if user_rate_is_ok(
-                        &history,
-                        &username,
-                        Duration::from_secs(args.min_wait_interval as u64),
-                        args.max_requests_hour,
-                    ) {
-                        match run_job(&args.rom, source.iter_lines(), args.native, &args.args) {
-                            Ok(video_path) => {
-                                // this means the encoding went well, let's log the size of the file and get to posting it
-                                let out_file = video_path.as_ref().join("out.mp4");
-                                log::info!(
-                                    "File is {} KB long",
-                                    std::fs::metadata(&out_file)?.size() / 1024
-                                );
-                                if !args.do_not_post {
-                                    log::info!("Posting to mastodon, replying to {post_id}");
-
-                                    // post on Mastodon
-                                    let url =
-                                        client.post_result(&username, &post_id, &out_file).await?;
-                                    client.clear_notification(&notif_id).await?;
-                                    log::info!("All done! {url}");
-                                    history.log(Utc::now(), &username, &url)?;
-                                } else {
-                                    log::info!("All done! (wink wink!)");
-                                }
-
-                                // skip to next notification
-                                continue;
-                            }
-                            Err(e) => {
-                                log::error!("Failed to run job for post {post_id}: {e}");
-                            }
-                        }
-                    } else {
-                        log::warn!("Request from {username} ignored due to rate limit");
-                        if !args.do_not_post {
-                            client
-                                .message_account(
-                                    &username,
-                                    "{GREETING}\n\nUnfortunately you're messaging me too much. \
-                                             Please wait some minutes before trying again. \
-                                             Sorry about that!",
-                                    Some(post_id),
-                                )
-                                .await?;
-                        }
-                    }
+    // a crash between posting a result and recording it shouldn't lead to a second reply
+    match until_shutdown(&mut shutdown, bot.client.recent_results()).await {
+        Some(Ok(results)) => reconcile(&bot, &results)?,
+        Some(Err(e)) => log::warn!("Can't check the latest results: {e:#}"),
+        None => {}
+    }
+
+    // pick up whatever was left when the bot was stopped
+    let unfinished = bot.jobs.lock().unwrap().unfinished();
+    for (status_id, status, job) in unfinished {
+        if status == JobStatus::Running {
+            log::warn!("Job for post {status_id} was interrupted, running it again");
+        } else {
+            log::info!("Resuming job for post {status_id}");
+        }
+        queue.requeue(&job.mention.account.acct.clone(), job);
+    }
+    let mut workers = JoinSet::new();
+    for _ in 0..args.workers {
+        workers.spawn(run_worker(bot.clone(), queue.clone(), shutdown.clone()));
+    }
+
+    let mut watcher = MentionWatcher::new(
+        &bot.client,
+        !args.no_streaming,
+        Duration::from_secs(args.poll_interval),
+    )
+    .await;
+
+    while !*shutdown.borrow() {
+        // keep up with whoever the bot account blocks or mutes
+        if Instant::now() >= blocks_due {
+            match until_shutdown(&mut shutdown, bot.client.get_blocked()).await {
+                Some(Ok((accounts, domains))) => access.set_blocked(&accounts, &domains),
+                Some(Err(e)) => log::warn!("Can't fetch blocked accounts: {e:#}"),
+                None => break,
+            }
+            blocks_due = Instant::now() + BLOCKS_REFRESH_INTERVAL;
+        }
+
+        // entries also expire while the bot is idle (or was stopped)
+        if bot.cache.is_some() && Instant::now() >= evict_due {
+            let bot = bot.clone();
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = bot.cache.as_ref().unwrap().evict() {
+                    log::warn!("Can't clean up the cache: {e:#}");
                 }
-                Err(e) => {
-                    match e {
-                        parser::ParseError::NoPreludeFound => {
-                            // Skip it
-                            log::debug!("Skipped {post_id}: doesn't include prelude")
-                        },
-                        parser::ParseError::Io(e) => {
-                            log::error!("Problem parsing content: {e}");
-                        }
-                        e => {
-                            log::warn!("Ignored {post_id}: {e}");
-                            if !args.do_not_post {
-                                client
-                                .message_account(
-                                    &username,
-                                    &format!("{GREETING}\n\nUnfortunately I couldn't parse your message. Reason: {e}"),
-                                    Some(post_id),
-                                )
-                                .await?;
-                            }
-                        }
-                    }
+            });
+            evict_due = Instant::now() + CACHE_EVICT_INTERVAL;
+        }
+
+        let notifications = match until_shutdown(
+            &mut shutdown,
+            bot.client.get_notifications(cursor.last_id()),
+        )
+        .await
+        {
+            Some(Ok(notifications)) => notifications,
+            Some(Err(e)) => {
+                log::error!("Can't fetch notifications: {e:#}");
+                until_shutdown(&mut shutdown, watcher.wait(&bot.client, false)).await;
+                continue;
+            }
+            None => break,
+        };
+        let found_any = !notifications.is_empty();
+        let mut deferred = false;
+
+        for mention in notifications {
+            if *shutdown.borrow() {
+                break;
+            }
+            if args.when_busy == WhenBusy::Defer && is_busy(&bot, &queue) {
+                // they'll still be there once there's room
+                log::warn!("Too busy, leaving the remaining mentions for later");
+                deferred = true;
+                break;
+            }
+
+            if let Err(denial) = access.check(&mention.account) {
+                log::info!(
+                    "Ignoring post {} from {}: {denial}",
+                    mention.status_id,
+                    mention.account.acct
+                );
+            } else if let Err(e) = accept_mention(&bot, &parse_config, &queue, &mention).await {
+                if retry::is_transient(&e) {
+                    // leave it (and everything after it) for later
+                    log::error!("Giving up on post {} for now: {e:#}", mention.status_id);
+                    break;
                 }
-            };
+                log::error!("Skipping post {}: {e:#}", mention.status_id);
+            }
 
             if !args.do_not_post {
-                client.clear_notification(&notif_id).await?;
+                let cleared = until_shutdown(
+                    &mut shutdown,
+                    bot.client.clear_notification(&mention.notification_id),
+                )
+                .await;
+                match cleared {
+                    Some(Ok(())) => {}
+                    Some(Err(e)) => log::warn!(
+                        "Can't clear notification {}: {e:#}",
+                        mention.notification_id
+                    ),
+                    // it'll be seen again after a restart, and skipped since it's on record
+                    None => break,
+                }
             }
+            cursor.advance(&mention.notification_id)?;
         }
 
-        time::sleep(Duration::from_secs(60)).await;
+        if deferred {
+            until_shutdown(&mut shutdown, wait_until_not_busy(&bot, &queue)).await;
+        } else {
+            until_shutdown(&mut shutdown, watcher.wait(&bot.client, found_any)).await;
+        }
+    }
+
+    let grace_period = Duration::from_secs(args.shutdown_grace_period);
+    log::info!("Not taking any more requests, giving running jobs {grace_period:?} to finish");
+    let finished = time::timeout(grace_period, async {
+        while workers.join_next().await.is_some() {}
+    })
+    .await
+    .is_ok();
+
+    // make sure that no history entry is being written
+    let _history = bot.history.lock().unwrap();
+
+    if finished {
+        if let Err(e) = fs::remove_dir_all(&work_dir) {
+            log::warn!("Can't remove {}: {e}", work_dir.display());
+        }
+        log::info!("Bye! 👋");
+        Ok(())
+    } else {
+        log::warn!("Some jobs didn't finish in time, they'll be run again on the next start");
+        // their encoders may still be writing there, so the files are left for the next start
+        // blocking tasks can't be cancelled, so don't wait for them
+        std::process::exit(EXIT_INTERRUPTED);
     }
 }
 
 async fn exec_cmd(
-    rom: impl AsRef<Path>,
+    job_config: &JobConfig,
     input: Option<impl AsRef<Path>>,
     output: impl AsRef<Path>,
     parse_config: &ParseConfig<'_>,
-    native: bool,
-    args: &Vec<String>,
 ) -> Result<()> {
     let mut input: Box<dyn Read> = match input {
         Some(f) => Box::new(File::open(f.as_ref())?),
@@ -203,10 +1045,27 @@ async fn exec_cmd(
 
     let source = parse_orca_code(&String::from_utf8(text)?, parse_config)?;
 
-    let dir = run_job(rom, source.iter_lines(), native, args)?;
+    if let Some(dir) = &job_config.stems_dir {
+        fs::create_dir_all(dir)?;
+    }
+
+    let job_output = run_job(job_config, source.iter_lines())?;
+
+    log::info!("Audio: {}", job_output.audio);
 
-    fs::copy(dir.as_ref().join("out.mp4"), output)?;
+    fs::copy(&job_output.file, output)?;
+
+    Ok(())
+}
 
+fn migrate_history_cmd(csv: &Path, database: &Path) -> Result<()> {
+    // opening the log would create it otherwise
+    fs::metadata(csv).with_context(|| format!("Can't open {}", csv.display()))?;
+    let log = Log::new(csv)?;
+    let count = SqliteHistory::open(database)?
+        .import(log.entries())
+        .with_context(|| format!("Can't migrate into {}", database.display()))?;
+    log::info!("Copied {count} entries into {}", database.display());
     Ok(())
 }
 
@@ -220,7 +1079,7 @@ async fn main() -> Result<()> {
     let args = cli::Cli::parse();
 
     match args.command {
-        SubCommands::Run(args) => run_cmd(args).await?,
+        SubCommands::Run(args) => run_cmd(*args).await?,
         SubCommands::Exec {
             rom,
             output,
@@ -228,6 +1087,8 @@ async fn main() -> Result<()> {
             max_line_length,
             max_num_lines,
             native,
+            mix,
+            stems,
             args,
         } => {
             let parse_config = ParseConfig {
@@ -235,9 +1096,90 @@ async fn main() -> Result<()> {
                 max_num_lines,
                 ..Default::default()
             };
-            exec_cmd(rom, input, output, &parse_config, native, &args).await?
+            let job_config = JobConfig {
+                rom,
+                native,
+                args,
+                mix: mix.mix_config(),
+                limits: EncodingLimits::default(),
+                gif_if_silent: false,
+                stems_dir: stems,
+                thumbnail: None,
+                work_dir: None,
+            };
+            exec_cmd(&job_config, input, output, &parse_config).await?
         }
+        SubCommands::MigrateHistory { csv, database } => migrate_history_cmd(&csv, &database)?,
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_counts_pending_jobs() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = Log::new(dir.path().join("history.csv")).unwrap();
+        let wait = Duration::from_secs(30);
+
+        assert!(user_rate_is_ok(&history, &[], "someone", wait, 2, false));
+        // a job which was just accepted, but hasn't been posted yet
+        let now = Utc::now();
+        assert!(!user_rate_is_ok(
+            &history,
+            &[now],
+            "someone",
+            wait,
+            2,
+            false
+        ));
+        let earlier = [
+            now - Duration::from_secs(60),
+            now - Duration::from_secs(120),
+        ];
+        assert!(!user_rate_is_ok(
+            &history, &earlier, "someone", wait, 2, false
+        ));
+        assert!(user_rate_is_ok(
+            &history,
+            &earlier[..1],
+            "someone",
+            wait,
+            2,
+            false
+        ));
+    }
+
+    #[test]
+    fn test_rate_counts_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut history = Log::new(dir.path().join("history.csv")).unwrap();
+        let wait = Duration::from_secs(30);
+
+        // a burst of posts which couldn't be parsed
+        for id in ["1", "2"] {
+            history
+                .log(&Record {
+                    outcome: Outcome::ParseError,
+                    url: None,
+                    ..Record::posted(id, "someone", "")
+                })
+                .unwrap();
+        }
+        assert!(user_rate_is_ok(&history, &[], "someone", wait, 2, false));
+        assert!(!user_rate_is_ok(&history, &[], "someone", wait, 2, true));
+
+        // the bot being busy isn't held against anyone
+        history
+            .log(&Record {
+                outcome: Outcome::Busy,
+                url: None,
+                ..Record::posted("3", "other", "")
+            })
+            .unwrap();
+        assert!(user_rate_is_ok(&history, &[], "other", wait, 2, true));
+    }
+}
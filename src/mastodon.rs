@@ -1,121 +1,835 @@
-use std::{path::Path, time::Duration};
+use std::{
+    cmp::Ordering,
+    fs,
+    future::Future,
+    path::Path,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Result};
+use chrono::{DateTime, Utc};
 use megalodon::{
-    entities::{notification::NotificationType, Attachment, StatusVisibility, UploadMedia},
+    entities::{
+        notification::NotificationType, Attachment, Notification, StatusVisibility, UploadMedia,
+    },
     megalodon::{
-        GetNotificationsInputOptions, PostStatusInputOptions, PostStatusOutput,
-        UploadMediaInputOptions,
+        AccountsInputOptions, GetAccountStatusesInputOptions, GetNotificationsInputOptions,
+        PostStatusInputOptions, PostStatusOutput, UploadMediaInputOptions,
     },
-    Megalodon,
+    streaming::Message,
+    Megalodon, SNS,
+};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, LINK, RETRY_AFTER},
+    multipart::{Form, Part},
 };
-use tokio::time;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{sync::mpsc, time};
+
+use crate::{
+    encoding::Thumbnail,
+    retry::{ApiError, Failure, RetryPolicy},
+};
+
+/// Maximum length of media descriptions accepted by Mastodon
+pub const MAX_DESCRIPTION_LENGTH: usize = 1500;
+
+/// Number of notifications requested at once
+const PAGE_SIZE: u32 = 40;
+
+/// Maximum number of pages fetched in one go
+const MAX_PAGES: usize = 25;
+
+/// Number of blocked/muted accounts requested at once
+const BLOCKS_PAGE_SIZE: u32 = 80;
+
+/// Shortest interval between polls, when the stream is down
+const MIN_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Even when the stream is up, poll every now and then, just in case something was missed
+const STREAMING_POLL_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How long to wait before trying to reconnect to the stream after it drops
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(2 * 60);
+
+/// First delay between checks of uploaded media, doubling every time
+const MEDIA_POLL_INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest delay between checks of uploaded media
+const MEDIA_POLL_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Compare IDs (which are numeric, but strings), so that older ones come first
+pub fn compare_ids(a: &str, b: &str) -> Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+/// Visibility levels, from the least to the most restrictive
+const VISIBILITY_LEVELS: [StatusVisibility; 4] = [
+    StatusVisibility::Public,
+    StatusVisibility::Unlisted,
+    StatusVisibility::Private,
+    StatusVisibility::Direct,
+];
+
+/// Pick the most restrictive of two visibilities (anything we don't know about is considered the most restrictive)
+pub fn most_restrictive(a: &StatusVisibility, b: &StatusVisibility) -> StatusVisibility {
+    let level = |v: &StatusVisibility| {
+        VISIBILITY_LEVELS
+            .iter()
+            .position(|l| l == v)
+            .unwrap_or(VISIBILITY_LEVELS.len())
+    };
+    if level(a) >= level(b) {
+        a.clone()
+    } else {
+        b.clone()
+    }
+}
+
+/// Maximum visibility of the posts made by the bot (replies never have a wider audience than the request)
+#[derive(Debug, Clone)]
+pub struct VisibilityPolicy {
+    /// For results
+    pub max_reply: StatusVisibility,
+    /// For error/warning messages
+    pub max_message: StatusVisibility,
+}
+
+/// Account which mentioned the bot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MentionAccount {
+    /// `user@domain` (or just `user` for local accounts)
+    pub acct: String,
+    pub url: String,
+    pub display_name: String,
+    pub bot: bool,
+}
+
+/// A post in which the bot was mentioned
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mention {
+    pub notification_id: String,
+    pub status_id: String,
+    pub status_url: Option<String>,
+    pub account: MentionAccount,
+    pub visibility: StatusVisibility,
+    pub language: Option<String>,
+    /// HTML content of the post
+    pub content: String,
+    pub spoiler_text: String,
+    pub attachments: Vec<Attachment>,
+    pub in_reply_to_id: Option<String>,
+    pub in_reply_to_account_id: Option<String>,
+    /// When the post was made
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl Mention {
+    /// Only notifications which come with an account and a status are mentions we can act upon
+    fn from_notification(notification: &Notification) -> Option<Self> {
+        let status = notification.status.as_ref()?;
+        let account = notification.account.as_ref()?;
+
+        Some(Self {
+            notification_id: notification.id.clone(),
+            status_id: status.id.clone(),
+            status_url: status.url.clone(),
+            account: MentionAccount {
+                acct: account.acct.clone(),
+                url: account.url.clone(),
+                display_name: account.display_name.clone(),
+                bot: account.bot,
+            },
+            visibility: status.visibility.clone(),
+            language: status.language.clone(),
+            content: status.content.clone(),
+            spoiler_text: status.spoiler_text.clone(),
+            attachments: status.media_attachments.clone(),
+            in_reply_to_id: status.in_reply_to_id.clone(),
+            in_reply_to_account_id: status.in_reply_to_account_id.clone(),
+            created_at: Some(status.created_at),
+        })
+    }
+}
+
+/// A result which the bot posted in reply to a request
+#[derive(Debug)]
+pub struct PostedResult {
+    pub request_id: String,
+    pub url: String,
+    /// Account which made the request
+    pub user: String,
+    pub time: DateTime<Utc>,
+}
+
+/// Keep a few calls in reserve when approaching the rate limit
+const RATE_LIMIT_MARGIN: u32 = 5;
+
+/// Decide whether a call which failed with a given HTTP status is worth retrying
+///
+/// Calls which aren't `idempotent` (e.g. posting) are only retried if they certainly didn't go through.
+fn classify_status(
+    status: Option<u16>,
+    error: anyhow::Error,
+    retry_after: Option<Duration>,
+    idempotent: bool,
+) -> Failure {
+    match status {
+        Some(429) => Failure::Transient { error, retry_after },
+        Some(408) | Some(503) => Failure::Transient {
+            error,
+            retry_after: None,
+        },
+        Some(500..=599) if idempotent => Failure::Transient {
+            error,
+            retry_after: None,
+        },
+        _ => Failure::Permanent(error),
+    }
+}
+
+/// Parse a `Retry-After` header, which is either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    if let Ok(secs) = value.trim().parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let until = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    // a date in the past means right away
+    Some((until.to_utc() - now).to_std().unwrap_or_default())
+}
+
+/// Media is still being processed
+fn is_partial_content(err: &megalodon::error::Error) -> bool {
+    matches!(
+        err,
+        megalodon::error::Error::OwnError(own_err)
+            if matches!(own_err.kind, megalodon::error::Kind::HTTPPartialContentError)
+    )
+}
+
+/// The server gave up on processing the media
+fn is_unprocessable(err: &megalodon::error::Error) -> bool {
+    matches!(
+        err,
+        megalodon::error::Error::OwnError(own_err) if own_err.status == Some(422)
+    )
+}
+
+/// Uploaded media which can't be attached to a post
+#[derive(Error, Debug)]
+pub enum MediaError {
+    #[error("the server failed to process media {id}")]
+    Failed { id: String },
+    #[error("media {id} still not processed after {waited:?}")]
+    TimedOut { id: String, waited: Duration },
+}
+
+/// `max_id` of the next (older) page, according to the `Link` header
+fn next_page(headers: &HeaderMap) -> Option<String> {
+    let links = headers.get(LINK)?.to_str().ok()?;
+    let next = links.split(',').find(|l| l.contains("rel=\"next\""))?;
+    let url = reqwest::Url::parse(next.split(['<', '>']).nth(1)?).ok()?;
+    url.query_pairs()
+        .find(|(key, _)| key == "max_id")
+        .map(|(_, value)| value.into_owned())
+}
+
+/// Events coming from the streaming API
+#[derive(Debug)]
+pub enum StreamEvent {
+    /// The account was mentioned
+    Mention,
+    /// The stream was closed
+    Closed,
+}
+
+/// Decides when it's time to look for new mentions, either because the stream told us so or because we're polling
+pub struct MentionWatcher {
+    tx: mpsc::Sender<StreamEvent>,
+    rx: mpsc::Receiver<StreamEvent>,
+    use_streaming: bool,
+    connected: bool,
+    last_connection: Instant,
+    poll_interval: Duration,
+    max_poll_interval: Duration,
+}
+
+impl MentionWatcher {
+    pub async fn new(client: &Client, use_streaming: bool, max_poll_interval: Duration) -> Self {
+        let (tx, rx) = mpsc::channel(16);
+        let mut watcher = Self {
+            tx,
+            rx,
+            use_streaming,
+            connected: false,
+            last_connection: Instant::now(),
+            poll_interval: MIN_POLL_INTERVAL.min(max_poll_interval),
+            max_poll_interval,
+        };
+        if use_streaming {
+            watcher.connect(client).await;
+        }
+        watcher
+    }
+
+    async fn connect(&mut self, client: &Client) {
+        log::info!("Connecting to the streaming API");
+        client.stream_mentions(self.tx.clone()).await;
+        self.connected = true;
+        self.last_connection = Instant::now();
+    }
+
+    /// Wait until there may be new mentions (`found_any` tells whether the last check returned anything)
+    pub async fn wait(&mut self, client: &Client, found_any: bool) {
+        if found_any {
+            self.poll_interval = MIN_POLL_INTERVAL.min(self.max_poll_interval);
+        }
+
+        if self.connected {
+            tokio::select! {
+                event = self.rx.recv() => match event {
+                    Some(StreamEvent::Mention) => {
+                        log::debug!("Got mention through stream");
+                        return;
+                    }
+                    Some(StreamEvent::Closed) | None => {
+                        log::warn!("Stream was closed, falling back to polling");
+                        self.connected = false;
+                    }
+                },
+                _ = time::sleep(STREAMING_POLL_INTERVAL) => return,
+            }
+        }
+
+        if self.use_streaming && self.last_connection.elapsed() > RECONNECT_INTERVAL {
+            // poll right away, in case we missed something while the stream was down
+            self.connect(client).await;
+            return;
+        }
+
+        time::sleep(self.poll_interval).await;
+
+        if !found_any {
+            // back off while nothing is happening
+            self.poll_interval = (self.poll_interval * 2).min(self.max_poll_interval);
+        }
+    }
+}
 
 pub struct Client {
     client: Box<dyn Megalodon + Send + Sync>,
+    sns: SNS,
+    visibility: VisibilityPolicy,
+    http: reqwest::Client,
+    instance_url: String,
+    access_token: String,
+    retry: RetryPolicy,
+    /// How long the server gets to process uploaded media
+    media_timeout: Duration,
+    /// Calls are held back until then, since the rate limit has been reached
+    rate_limited_until: Mutex<Option<DateTime<Utc>>>,
 }
 
 impl Client {
-    pub fn new(instance_url: String, access_token: String) -> Result<Client> {
+    /// Create a client for the given server type, or detect it if `None`
+    pub async fn new(
+        instance_url: String,
+        access_token: String,
+        sns: Option<SNS>,
+        visibility: VisibilityPolicy,
+        retry: RetryPolicy,
+        media_timeout: Duration,
+    ) -> Result<Client> {
+        let sns = match sns {
+            Some(sns) => sns,
+            None => {
+                let sns = megalodon::detector(&instance_url).await?;
+                log::info!("Detected server type: {sns:?}");
+                sns
+            }
+        };
+
         Ok(Client {
+            visibility,
             client: megalodon::generator(
-                megalodon::SNS::Mastodon,
-                instance_url,
-                Some(access_token),
+                sns.clone(),
+                instance_url.clone(),
+                Some(access_token.clone()),
                 None,
             )?,
+            sns,
+            http: reqwest::Client::new(),
+            instance_url,
+            access_token,
+            retry,
+            media_timeout,
+            rate_limited_until: Mutex::new(None),
         })
     }
 
-    pub async fn get_notifications(
+    /// How long until the rate limit is reset (if it has been reached)
+    fn rate_limit_delay(&self) -> Option<Duration> {
+        self.rate_limited_until
+            .lock()
+            .unwrap()
+            .and_then(|until| (until - Utc::now()).to_std().ok())
+    }
+
+    /// Keep track of the rate limit headers sent by the server
+    fn update_rate_limit(&self, headers: &HeaderMap) {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+        };
+        let remaining = header("x-ratelimit-remaining").and_then(|v| v.parse::<u32>().ok());
+        let reset = header("x-ratelimit-reset")
+            .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+            .map(|t| t.to_utc());
+
+        if let (Some(remaining), Some(reset)) = (remaining, reset) {
+            *self.rate_limited_until.lock().unwrap() = if remaining <= RATE_LIMIT_MARGIN {
+                log::warn!("Only {remaining} API calls left, holding back until {reset}");
+                Some(reset)
+            } else {
+                None
+            };
+        }
+    }
+
+    /// Decide whether a failed call is worth retrying
+    fn classify(&self, err: megalodon::error::Error, idempotent: bool) -> Failure {
+        let status = match &err {
+            megalodon::error::Error::OwnError(own_err) => own_err.status,
+            // the request never made it to the server
+            megalodon::error::Error::RequestError(req_err) if req_err.is_connect() => {
+                return Failure::Transient {
+                    error: err.into(),
+                    retry_after: None,
+                }
+            }
+            // the request may or may not have been handled
+            megalodon::error::Error::RequestError(req_err) if req_err.is_timeout() => {
+                return if idempotent {
+                    Failure::Transient {
+                        error: err.into(),
+                        retry_after: None,
+                    }
+                } else {
+                    Failure::Permanent(err.into())
+                };
+            }
+            megalodon::error::Error::RequestError(req_err) => req_err.status().map(|s| s.as_u16()),
+            _ => None,
+        };
+        // megalodon doesn't pass on the headers of failed responses, so `Retry-After` can't be
+        // honored here, and the reset time of the rate limit is the best guess we have
+        classify_status(status, err.into(), self.rate_limit_delay(), idempotent)
+    }
+
+    /// Call the API, retrying on transient errors and keeping track of rate limits
+    async fn call<T, F, Fut>(&self, what: &str, f: F) -> Result<T, ApiError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<megalodon::response::Response<T>, megalodon::error::Error>>,
+    {
+        self.call_response(what, true, f).await.map(|res| res.json)
+    }
+
+    /// Same as `call`, for calls which mustn't be repeated if they may have gone through (e.g. posting)
+    async fn call_once<T, F, Fut>(&self, what: &str, f: F) -> Result<T, ApiError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<megalodon::response::Response<T>, megalodon::error::Error>>,
+    {
+        self.call_response(what, false, f).await.map(|res| res.json)
+    }
+
+    /// Same as `call`, but keeping the whole response (e.g. for the headers)
+    async fn call_response<T, F, Fut>(
         &self,
-    ) -> Result<Vec<(String, String, (String, String), String)>> {
-        Ok(self
-            .client
-            .get_notifications(Some(&GetNotificationsInputOptions {
-                limit: None,
-                max_id: None,
-                since_id: None,
-                min_id: None,
-                exclude_types: Some(vec![
-                    // only keep Mention
-                    NotificationType::Follow,
-                    NotificationType::FollowRequest,
-                    NotificationType::Reblog,
-                    NotificationType::Favourite,
-                    NotificationType::PollVote,
-                    NotificationType::PollExpired,
-                    NotificationType::Status,
-                    NotificationType::Reaction,
-                    NotificationType::Update,
-                    NotificationType::Move,
-                    NotificationType::AdminSignup,
-                    NotificationType::AdminReport,
-                    NotificationType::GroupInvited,
-                    NotificationType::App,
-                    NotificationType::Unknown,
-                ]),
+        what: &str,
+        idempotent: bool,
+        mut f: F,
+    ) -> Result<megalodon::response::Response<T>, ApiError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<megalodon::response::Response<T>, megalodon::error::Error>>,
+    {
+        self.retry
+            .run(what, || {
+                let fut = f();
+                async move {
+                    if let Some(delay) = self.rate_limit_delay() {
+                        time::sleep(delay).await;
+                    }
+                    match fut.await {
+                        Ok(res) => {
+                            self.update_rate_limit(&res.header);
+                            Ok(res)
+                        }
+                        Err(err) => Err(self.classify(err, idempotent)),
+                    }
+                }
+            })
+            .await
+    }
+
+    /// Whether the server understands notification type filters
+    fn supports_exclude_types(&self) -> bool {
+        matches!(self.sns, SNS::Mastodon | SNS::Pleroma)
+    }
+
+    /// Whether the server lets us page forward with `min_id` (otherwise we use `since_id` and page backwards)
+    fn supports_min_id(&self) -> bool {
+        matches!(self.sns, SNS::Mastodon | SNS::Pleroma)
+    }
+
+    /// Whether single notifications can be dismissed
+    fn supports_dismiss(&self) -> bool {
+        matches!(self.sns, SNS::Mastodon | SNS::Pleroma | SNS::Friendica)
+    }
+
+    /// Whether media can be given a custom thumbnail and a focal point
+    fn supports_thumbnails(&self) -> bool {
+        matches!(self.sns, SNS::Mastodon)
+    }
+
+    /// Fetch all pending mentions, oldest first, going through as many pages as needed
+    pub async fn get_notifications(&self, since_id: Option<&str>) -> Result<Vec<Mention>> {
+        let mut notifications = Vec::new();
+        let forward = since_id.is_some() && self.supports_min_id();
+        let mut min_id = since_id.filter(|_| forward).map(String::from);
+        let mut max_id = None;
+
+        for _ in 0..MAX_PAGES {
+            let options = GetNotificationsInputOptions {
+                limit: Some(PAGE_SIZE),
+                max_id: max_id.clone(),
+                since_id: since_id.filter(|_| !forward).map(String::from),
+                min_id: min_id.clone(),
+                exclude_types: self.supports_exclude_types().then(|| {
+                    vec![
+                        // only keep Mention
+                        NotificationType::Follow,
+                        NotificationType::FollowRequest,
+                        NotificationType::Reblog,
+                        NotificationType::Favourite,
+                        NotificationType::PollVote,
+                        NotificationType::PollExpired,
+                        NotificationType::Status,
+                        NotificationType::Reaction,
+                        NotificationType::Update,
+                        NotificationType::Move,
+                        NotificationType::AdminSignup,
+                        NotificationType::AdminReport,
+                        NotificationType::GroupInvited,
+                        NotificationType::App,
+                        NotificationType::Unknown,
+                    ]
+                }),
                 account_id: None,
-            }))
-            .await?
-            .json
+            };
+            let page = self
+                .call("Fetching notifications", || {
+                    self.client.get_notifications(Some(&options))
+                })
+                .await?;
+            let num_results = page.len();
+
+            if forward {
+                // moving forward from the cursor
+                min_id = page
+                    .iter()
+                    .map(|n| n.id.clone())
+                    .max_by(|a, b| compare_ids(a, b));
+            } else {
+                // start from the most recent ones and move back (until the cursor, if any)
+                max_id = page
+                    .iter()
+                    .map(|n| n.id.clone())
+                    .min_by(|a, b| compare_ids(a, b));
+            }
+
+            notifications.extend(page);
+
+            if num_results < PAGE_SIZE as usize {
+                break;
+            }
+        }
+
+        notifications.sort_by(|a, b| compare_ids(&a.id, &b.id));
+        notifications.dedup_by(|a, b| a.id == b.id);
+
+        Ok(notifications
             .iter()
-            .filter(|n| n.account.is_some() && n.status.is_some())
-            .map(|n| {
-                let status = n.status.as_ref().unwrap();
-                let account = n.account.as_ref().unwrap();
-                (
-                    n.id.clone(),
-                    status.id.clone(),
-                    (account.acct.clone(), account.url.clone()),
-                    status.content.clone(),
-                )
+            // not all servers filter by type
+            .filter(|n| matches!(n.r#type, NotificationType::Mention))
+            .filter_map(Mention::from_notification)
+            .collect())
+    }
+
+    /// Go through all the pages of a list of accounts (or domains)
+    async fn get_all<T, F, Fut>(&self, what: &str, f: F) -> Result<Vec<T>>
+    where
+        F: Fn(AccountsInputOptions) -> Fut,
+        Fut:
+            Future<Output = Result<megalodon::response::Response<Vec<T>>, megalodon::error::Error>>,
+    {
+        let mut items = Vec::new();
+        let mut max_id = None;
+
+        for _ in 0..MAX_PAGES {
+            let res = self
+                .call_response(what, true, || {
+                    f(AccountsInputOptions {
+                        limit: Some(BLOCKS_PAGE_SIZE),
+                        max_id: max_id.clone(),
+                        since_id: None,
+                    })
+                })
+                .await?;
+            max_id = next_page(&res.header);
+            items.extend(res.json);
+
+            if max_id.is_none() {
+                break;
+            }
+        }
+
+        Ok(items)
+    }
+
+    /// Accounts blocked or muted by the bot account, and the domains it blocks
+    pub async fn get_blocked(&self) -> Result<(Vec<String>, Vec<String>)> {
+        let mut accounts = self
+            .get_all("Fetching blocks", |options| async move {
+                self.client.get_blocks(Some(&options)).await
+            })
+            .await?;
+        accounts.extend(
+            self.get_all("Fetching mutes", |options| async move {
+                self.client.get_mutes(Some(&options)).await
+            })
+            .await?,
+        );
+        let domains = self
+            .get_all("Fetching domain blocks", |options| async move {
+                self.client.get_domain_blocks(Some(&options)).await
+            })
+            .await?;
+
+        Ok((accounts.into_iter().map(|a| a.acct).collect(), domains))
+    }
+
+    /// Results recently posted by the bot account, newest first
+    pub async fn recent_results(&self) -> Result<Vec<PostedResult>> {
+        let me = self
+            .call("Fetching own account", || {
+                self.client.verify_account_credentials()
+            })
+            .await?;
+        let options = GetAccountStatusesInputOptions {
+            limit: Some(PAGE_SIZE),
+            exclude_reblogs: Some(true),
+            only_media: Some(true),
+            ..Default::default()
+        };
+        let statuses = self
+            .call("Fetching own posts", || {
+                self.client
+                    .get_account_statuses(me.id.clone(), Some(&options))
+            })
+            .await?;
+
+        Ok(statuses
+            .into_iter()
+            // error messages come without media
+            .filter(|s| !s.media_attachments.is_empty())
+            .filter_map(|s| {
+                let user = s
+                    .mentions
+                    .iter()
+                    .find(|m| Some(&m.id) == s.in_reply_to_account_id.as_ref())?
+                    .acct
+                    .clone();
+                Some(PostedResult {
+                    request_id: s.in_reply_to_id?,
+                    url: s.url.unwrap_or(s.uri),
+                    user,
+                    time: s.created_at,
+                })
             })
             .collect())
     }
 
+    /// Listen to the user stream in the background, sending an event whenever the account is mentioned
+    pub async fn stream_mentions(&self, tx: mpsc::Sender<StreamEvent>) {
+        let streaming = self.client.user_streaming().await;
+        let closed_tx = tx.clone();
+
+        tokio::spawn(async move {
+            streaming
+                .listen(Box::new(move |message| {
+                    let tx = tx.clone();
+                    Box::pin(async move {
+                        if let Message::Notification(notification) = message {
+                            if matches!(notification.r#type, NotificationType::Mention) {
+                                // if the receiving end is gone there's nobody to tell
+                                let _ = tx.send(StreamEvent::Mention).await;
+                            }
+                        }
+                    })
+                }))
+                .await;
+            let _ = closed_tx.send(StreamEvent::Closed).await;
+        });
+    }
+
     pub async fn clear_notification(&self, id: &str) -> Result<()> {
-        self.client.dismiss_notification(id.into()).await?;
+        // otherwise, the cursor will make sure that it's not processed again
+        if self.supports_dismiss() {
+            self.call("Dismissing notification", || {
+                self.client.dismiss_notification(id.into())
+            })
+            .await?;
+        }
         Ok(())
     }
 
+    /// Poll the server until it's done processing the media, with increasing delays
     async fn wait_until_media_uploaded(&self, id: &str) -> Result<Attachment> {
+        let start = Instant::now();
+        let mut delay = MEDIA_POLL_INITIAL_DELAY;
+        let mut failures = 0;
         loop {
-            let res = self.client.get_media(id.to_string()).await;
-            return match res {
-                Ok(res) => Ok(res.json()),
-                Err(err) => match err {
-                    megalodon::error::Error::OwnError(ref own_err) => match own_err.kind {
-                        megalodon::error::Kind::HTTPPartialContentError => {
-                            // wait a few seconds to see if the file has already been uploaded
-                            time::sleep(Duration::from_secs(5)).await;
-                            continue;
+            match self.client.get_media(id.to_string()).await {
+                Ok(res) => return Ok(res.json()),
+                Err(err) if is_partial_content(&err) => {
+                    // still processing, unless we've been waiting for too long
+                    let waited = start.elapsed();
+                    if waited >= self.media_timeout {
+                        return Err(MediaError::TimedOut {
+                            id: id.into(),
+                            waited,
+                        }
+                        .into());
+                    }
+                    time::sleep(delay.min(self.media_timeout - waited)).await;
+                    delay = (delay * 2).min(MEDIA_POLL_MAX_DELAY);
+                }
+                Err(err) if is_unprocessable(&err) => {
+                    return Err(MediaError::Failed { id: id.into() }.into())
+                }
+                Err(err) => match self.classify(err, true) {
+                    Failure::Transient { error, retry_after }
+                        if failures < self.retry.max_retries =>
+                    {
+                        failures += 1;
+                        let delay = retry_after.unwrap_or_else(|| self.retry.delay(failures));
+                        log::warn!("Checking media failed ({error:#}), retrying in {delay:?}");
+                        time::sleep(delay).await;
+                    }
+                    Failure::Transient { error, .. } => {
+                        return Err(ApiError::Exhausted {
+                            what: "Checking media".into(),
+                            attempts: failures + 1,
+                            error,
+                        }
+                        .into())
+                    }
+                    Failure::Permanent(error) => {
+                        return Err(ApiError::Permanent {
+                            what: "Checking media".into(),
+                            error,
                         }
-                        _ => Err(err.into()),
-                    },
-                    _ => Err(err.into()),
+                        .into())
+                    }
                 },
-            };
+            }
         }
     }
 
+    /// Set a custom thumbnail for a video (megalodon doesn't support it, so we call the API directly)
+    async fn set_thumbnail(&self, media_id: &str, thumbnail: &Thumbnail) -> Result<()> {
+        let (x, y) = thumbnail.focus;
+        let data = fs::read(&thumbnail.path)?;
+
+        self.retry
+            .run("Setting thumbnail", || {
+                let data = data.clone();
+                async move {
+                    let form = Form::new()
+                        .part(
+                            "thumbnail",
+                            Part::bytes(data)
+                                .file_name("thumbnail.png")
+                                .mime_str("image/png")
+                                .map_err(|e| Failure::Permanent(e.into()))?,
+                        )
+                        .text("focus", format!("{x:.2},{y:.2}"));
+
+                    let res = self
+                        .http
+                        .put(format!(
+                            "{}/api/v1/media/{media_id}",
+                            self.instance_url.trim_end_matches('/')
+                        ))
+                        .bearer_auth(&self.access_token)
+                        .multipart(form)
+                        .send()
+                        .await
+                        .map_err(|e| Failure::Transient {
+                            error: e.into(),
+                            retry_after: None,
+                        })?;
+
+                    let status = res.status();
+                    if status.is_success() {
+                        return Ok(());
+                    }
+
+                    let retry_after = res
+                        .headers()
+                        .get(RETRY_AFTER)
+                        .and_then(|v| v.to_str().ok())
+                        .and_then(|v| parse_retry_after(v, Utc::now()));
+                    Err(classify_status(
+                        Some(status.as_u16()),
+                        anyhow!("HTTP {status}"),
+                        retry_after,
+                        true,
+                    ))
+                }
+            })
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn post_result(
         &self,
-        username: &str,
-        id: &str,
+        mention: &Mention,
         video_path: impl AsRef<Path>,
+        description: &str,
+        text: &str,
+        language: &str,
+        thumbnail: Option<&Thumbnail>,
     ) -> Result<String> {
+        let options = UploadMediaInputOptions {
+            description: Some(description.into()),
+            focus: thumbnail
+                .filter(|_| self.supports_thumbnails())
+                .map(|t| format!("{:.2},{:.2}", t.focus.0, t.focus.1)),
+        };
         let media = self
-            .client
-            .upload_media(
-                video_path.as_ref().to_string_lossy().into(),
-                Some(&UploadMediaInputOptions {
-                    description: Some("Result of the Orca code".into()),
-                    focus: None,
-                }),
-            )
-            .await?
-            .json;
+            .call("Uploading media", || {
+                self.client
+                    .upload_media(video_path.as_ref().to_string_lossy().into(), Some(&options))
+            })
+            .await?;
 
         log::info!("Uploading media");
 
@@ -130,48 +844,110 @@ impl Client {
         };
         log::info!("Media {} uploaded", res);
 
-        let status = format!("I ran @{username}'s program and here's the result!");
+        if let Some(thumbnail) = thumbnail.filter(|_| self.supports_thumbnails()) {
+            if let Err(e) = self.set_thumbnail(&res, thumbnail).await {
+                log::warn!("Couldn't set thumbnail of media {res}: {e}");
+            }
+        }
 
+        let options = PostStatusInputOptions {
+            media_ids: Some(vec![res]),
+            poll: None,
+            in_reply_to_id: Some(mention.status_id.clone()),
+            sensitive: Some(false),
+            spoiler_text: None,
+            visibility: Some(most_restrictive(
+                &mention.visibility,
+                &self.visibility.max_reply,
+            )),
+            scheduled_at: None,
+            language: Some(language.into()),
+            quote_id: None,
+        };
         let status = self
-            .client
-            .post_status(
-                status,
-                Some(&PostStatusInputOptions {
-                    media_ids: Some(vec![res]),
-                    poll: None,
-                    in_reply_to_id: Some(id.into()),
-                    sensitive: Some(false),
-                    spoiler_text: None,
-                    visibility: Some(StatusVisibility::Public),
-                    scheduled_at: None,
-                    language: Some("en".into()),
-                    quote_id: None,
-                }),
-            )
-            .await?
-            .json;
+            .call_once("Posting result", || {
+                self.client.post_status(text.to_string(), Some(&options))
+            })
+            .await?;
 
         match status {
-            PostStatusOutput::Status(status) => Ok(status.url.unwrap()),
+            PostStatusOutput::Status(status) => Ok(status.url.unwrap_or(status.uri)),
             PostStatusOutput::ScheduledStatus(_scheduled_status) => {
                 Err(anyhow!("Shouldn't be getting a scheduled status!"))
             }
         }
     }
 
-    pub async fn message_account(&self, account: &str, message: &str, in_reply_to_id: Option<String>) -> Result<()> {
-        self.client.post_status(format!("@{account} {message}"), Some(&PostStatusInputOptions {
+    pub async fn message_account(
+        &self,
+        mention: &Mention,
+        message: &str,
+        language: &str,
+    ) -> Result<()> {
+        let options = PostStatusInputOptions {
             media_ids: None,
             poll: None,
-            in_reply_to_id,
+            in_reply_to_id: Some(mention.status_id.clone()),
             sensitive: Some(false),
             spoiler_text: None,
-            visibility: Some(StatusVisibility::Direct),
+            visibility: Some(most_restrictive(
+                &mention.visibility,
+                &self.visibility.max_message,
+            )),
             scheduled_at: None,
-            language: Some("en".into()),
+            language: Some(language.into()),
             quote_id: None,
-        })).await?;
+        };
+        self.call_once("Messaging account", || {
+            self.client.post_status(
+                format!("@{} {message}", mention.account.acct),
+                Some(&options),
+            )
+        })
+        .await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_most_restrictive() {
+        use StatusVisibility::*;
+
+        assert_eq!(most_restrictive(&Public, &Direct), Direct);
+        assert_eq!(most_restrictive(&Private, &Unlisted), Private);
+        assert_eq!(most_restrictive(&Unlisted, &Unlisted), Unlisted);
+        assert_eq!(most_restrictive(&Public, &Public), Public);
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = DateTime::parse_from_rfc3339("2015-10-21T07:28:00Z")
+            .unwrap()
+            .to_utc();
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:30:00 GMT", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_compare_ids() {
+        assert_eq!(compare_ids("99", "100"), Ordering::Less);
+        assert_eq!(compare_ids("110", "109"), Ordering::Greater);
+        assert_eq!(compare_ids("42", "42"), Ordering::Equal);
+    }
+}
@@ -1,7 +1,11 @@
+use std::fmt;
+
 use anyhow::Result;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct OrcaSource {
     data: Vec<char>,
     width: u8,
@@ -33,6 +37,19 @@ impl OrcaSource {
             ptr: 0,
         }
     }
+
+    /// Width and height of the grid
+    pub fn size(&self) -> (usize, usize) {
+        let width = self.width as usize;
+        (width, self.data.len() / width)
+    }
+}
+
+impl fmt::Display for OrcaSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lines: Vec<String> = self.iter_lines().map(|l| l.iter().collect()).collect();
+        write!(f, "{}", lines.join("\n"))
+    }
 }
 
 #[derive(Error, Debug)]
@@ -128,6 +145,14 @@ mod tests {
         assert!(width == 16);
     }
 
+    #[test]
+    fn test_source_display() {
+        let input = ".....C8.........\n......8TCDGCGDCE\n....81X..D..C2..";
+        let source = parse_orca_code(input, &DEFAULT_PARSE_CONFIG).unwrap();
+        assert_eq!(source.size(), (16, 3));
+        assert_eq!(source.to_string(), input);
+    }
+
     #[test]
     fn test_parsing_html_ok() {
         let input = "<p><span class=\"h-card\" translate=\"no\"><a href=\"https://fedi.turbofish.cc/@orcabot\" class=\"u-url mention\">@<span>orcabot</span></a></span> <a href=\"https://mastodon.xyz/tags/run\" class=\"mention hashtag status-link\" rel=\"nofollow noopener noreferrer\" target=\"_blank\">#<span>run</span></a><br />.....C8.........<br />......8TCDGCGDCE<br />....81X..D..C2..<br />..........Y..A4.<br />...........=0...</p>";
@@ -0,0 +1,156 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use thiserror::Error;
+use tokio::sync::Notify;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum QueueError {
+    #[error("the job queue is full")]
+    Full,
+    #[error("too many jobs queued for this account")]
+    UserLimit,
+}
+
+#[derive(Debug)]
+struct Jobs<T> {
+    /// Users with queued jobs, in the order they'll be served
+    users: VecDeque<String>,
+    per_user: HashMap<String, VecDeque<T>>,
+    len: usize,
+}
+
+/// Bounded job queue, which takes turns between users so that nobody can hog it
+#[derive(Debug)]
+pub struct JobQueue<T> {
+    capacity: usize,
+    max_per_user: usize,
+    jobs: Mutex<Jobs<T>>,
+    /// Signaled when a job is added
+    added: Notify,
+    /// Signaled when a job is taken
+    taken: Notify,
+}
+
+impl<T> JobQueue<T> {
+    pub fn new(capacity: usize, max_per_user: usize) -> Self {
+        Self {
+            capacity,
+            max_per_user,
+            jobs: Mutex::new(Jobs {
+                users: VecDeque::new(),
+                per_user: HashMap::new(),
+                len: 0,
+            }),
+            added: Notify::new(),
+            taken: Notify::new(),
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.jobs.lock().unwrap().len >= self.capacity
+    }
+
+    pub fn push(&self, user: &str, job: T) -> Result<(), QueueError> {
+        self.push_inner(user, job, true)
+    }
+
+    /// Put back a job which had already been accepted, regardless of limits
+    pub fn requeue(&self, user: &str, job: T) {
+        // can't fail, since limits aren't checked
+        let _ = self.push_inner(user, job, false);
+    }
+
+    fn push_inner(&self, user: &str, job: T, check_limits: bool) -> Result<(), QueueError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if check_limits && jobs.len >= self.capacity {
+            return Err(QueueError::Full);
+        }
+
+        let user_jobs = jobs.per_user.entry(user.into()).or_default();
+        if check_limits && user_jobs.len() >= self.max_per_user {
+            return Err(QueueError::UserLimit);
+        }
+        user_jobs.push_back(job);
+        if user_jobs.len() == 1 {
+            jobs.users.push_back(user.into());
+        }
+        jobs.len += 1;
+
+        self.added.notify_one();
+        Ok(())
+    }
+
+    /// Take the next job, from the user whose turn it is
+    pub fn try_pop(&self) -> Option<T> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let user = jobs.users.pop_front()?;
+        let user_jobs = jobs.per_user.get_mut(&user)?;
+        let job = user_jobs.pop_front()?;
+
+        if user_jobs.is_empty() {
+            jobs.per_user.remove(&user);
+        } else {
+            // back to the end of the line
+            jobs.users.push_back(user);
+        }
+        jobs.len -= 1;
+
+        self.taken.notify_one();
+        Some(job)
+    }
+
+    /// Wait until there's a job to take
+    pub async fn pop(&self) -> T {
+        loop {
+            if let Some(job) = self.try_pop() {
+                return job;
+            }
+            self.added.notified().await;
+        }
+    }
+
+    /// Wait until there's room for at least one more job
+    pub async fn wait_for_space(&self) {
+        while self.is_full() {
+            self.taken.notified().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fairness() {
+        let queue = JobQueue::new(5, 3);
+        queue.push("a", 1).unwrap();
+        queue.push("a", 2).unwrap();
+        queue.push("a", 3).unwrap();
+        assert_eq!(queue.push("a", 4), Err(QueueError::UserLimit));
+        queue.push("b", 5).unwrap();
+        queue.push("c", 6).unwrap();
+        assert!(queue.is_full());
+        assert_eq!(queue.push("d", 7), Err(QueueError::Full));
+
+        // everyone gets a turn
+        let order: Vec<_> = std::iter::from_fn(|| queue.try_pop()).collect();
+        assert_eq!(order, vec![1, 5, 6, 2, 3]);
+        assert!(queue.try_pop().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_pop_waits() {
+        let queue = std::sync::Arc::new(JobQueue::new(1, 1));
+        let worker = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.pop().await }
+        });
+        queue.push("a", 42).unwrap();
+        assert_eq!(worker.await.unwrap(), 42);
+        queue.wait_for_space().await;
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+/// Counts events over a sliding window of time, up to a limit
+#[derive(Debug)]
+pub struct SlidingWindow {
+    window: Duration,
+    limit: usize,
+    /// When the events in the window happened, oldest first
+    events: VecDeque<Instant>,
+}
+
+impl SlidingWindow {
+    pub fn new(window: Duration, limit: usize) -> Self {
+        Self {
+            window,
+            limit,
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Forget the events which are out of the window
+    fn expire(&mut self, now: Instant) {
+        while let Some(&oldest) = self.events.front() {
+            if now.saturating_duration_since(oldest) < self.window {
+                break;
+            }
+            self.events.pop_front();
+        }
+    }
+
+    /// Whether another event would fit in the window
+    pub fn has_room(&mut self, now: Instant) -> bool {
+        self.expire(now);
+        self.events.len() < self.limit
+    }
+
+    pub fn record(&mut self, now: Instant) {
+        self.events.push_back(now);
+    }
+
+    /// When there will be room for another event (`now` if there already is)
+    pub fn next_free(&mut self, now: Instant) -> Instant {
+        self.expire(now);
+        if self.events.len() < self.limit {
+            return now;
+        }
+        // the oldest events which have to go first
+        self.events[self.events.len() - self.limit] + self.window
+    }
+}
+
+/// Recent requests of each account, to enforce per-account limits
+#[derive(Debug)]
+pub struct UserRates {
+    /// How far back requests are remembered
+    window: TimeDelta,
+    /// When each account made its requests, oldest first
+    users: HashMap<String, VecDeque<DateTime<Utc>>>,
+}
+
+impl UserRates {
+    pub fn new(window: TimeDelta) -> Self {
+        Self {
+            window,
+            users: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, user: &str, time: DateTime<Utc>) {
+        let times = self.users.entry(user.into()).or_default();
+        // entries are usually in order, but the clock may have gone back
+        let pos = times.partition_point(|t| *t <= time);
+        times.insert(pos, time);
+
+        // forget whatever is out of the window by now, including accounts which went quiet
+        let start = *times.back().unwrap() - self.window;
+        while times.front().is_some_and(|t| *t <= start) {
+            times.pop_front();
+        }
+        self.users
+            .retain(|_, times| times.back().is_some_and(|t| *t > start));
+    }
+
+    /// Time of the latest request of an account
+    pub fn last(&self, user: &str) -> Option<DateTime<Utc>> {
+        self.users.get(user)?.back().copied()
+    }
+
+    /// Number of requests of an account after `start`
+    pub fn count_since(&self, user: &str, start: DateTime<Utc>) -> usize {
+        self.users.get(user).map_or(0, |times| {
+            times.len() - times.partition_point(|t| *t <= start)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sliding_window() {
+        let start = Instant::now();
+        let hour = Duration::from_secs(60 * 60);
+        let mut window = SlidingWindow::new(hour, 2);
+
+        assert!(window.has_room(start));
+        window.record(start);
+        window.record(start + Duration::from_secs(60));
+        assert!(!window.has_room(start + Duration::from_secs(120)));
+        assert_eq!(
+            window.next_free(start + Duration::from_secs(120)),
+            start + hour
+        );
+
+        // the first event is out of the window exactly one hour later
+        assert!(window.has_room(start + hour));
+        window.record(start + hour);
+        assert_eq!(
+            window.next_free(start + hour),
+            start + hour + Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn test_user_rates() {
+        let start: DateTime<Utc> = "2025-01-10T10:00:00Z".parse().unwrap();
+        let minutes = TimeDelta::minutes;
+        let mut rates = UserRates::new(TimeDelta::hours(1));
+
+        rates.record("a", start);
+        rates.record("b", start + minutes(5));
+        rates.record("a", start + minutes(20));
+        // out of order
+        rates.record("a", start + minutes(10));
+
+        assert_eq!(rates.last("a"), Some(start + minutes(20)));
+        assert_eq!(rates.last("c"), None);
+        // the start of the window itself is excluded
+        assert_eq!(rates.count_since("a", start), 2);
+        assert_eq!(rates.count_since("a", start - minutes(1)), 3);
+        assert_eq!(rates.count_since("a", start + minutes(20)), 0);
+        // other accounts don't count
+        assert_eq!(rates.count_since("b", start - minutes(1)), 1);
+        assert_eq!(rates.count_since("c", start - minutes(1)), 0);
+
+        // an hour after the first one, it's gone
+        rates.record("a", start + minutes(60));
+        assert_eq!(rates.count_since("a", start - minutes(1)), 3);
+        assert_eq!(rates.users["a"].len(), 3);
+
+        // accounts which didn't come back are forgotten too
+        assert!(rates.users.contains_key("b"));
+        rates.record("a", start + minutes(70));
+        assert!(!rates.users.contains_key("b"));
+        assert_eq!(rates.last("b"), None);
+    }
+}
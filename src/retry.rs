@@ -0,0 +1,163 @@
+use std::{future::Future, time::Duration};
+
+use thiserror::Error;
+use tokio::time;
+
+/// Outcome of a failed attempt
+#[derive(Debug)]
+pub enum Failure {
+    /// Worth trying again, possibly after a delay requested by the server
+    Transient {
+        error: anyhow::Error,
+        retry_after: Option<Duration>,
+    },
+    /// No point in trying again
+    Permanent(anyhow::Error),
+}
+
+#[derive(Error, Debug)]
+pub enum ApiError {
+    #[error("{what} failed after {attempts} attempts: {error:#}")]
+    Exhausted {
+        what: String,
+        attempts: u32,
+        error: anyhow::Error,
+    },
+    #[error("{what} failed: {error:#}")]
+    Permanent { what: String, error: anyhow::Error },
+}
+
+impl ApiError {
+    /// Whether the call may succeed if retried later on
+    pub fn is_transient(&self) -> bool {
+        matches!(self, ApiError::Exhausted { .. })
+    }
+}
+
+/// Whether an error comes from an API call which may succeed later on
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<ApiError>()
+        .is_some_and(ApiError::is_transient)
+}
+
+/// Exponential backoff settings
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `attempt` (starting at 1)
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.initial_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay)
+    }
+
+    /// Run `f` until it succeeds, fails permanently or we run out of retries
+    pub async fn run<T, F, Fut>(&self, what: &str, mut f: F) -> Result<T, ApiError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Failure>>,
+    {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match f().await {
+                Ok(res) => return Ok(res),
+                Err(Failure::Permanent(error)) => {
+                    return Err(ApiError::Permanent {
+                        what: what.into(),
+                        error,
+                    })
+                }
+                Err(Failure::Transient { error, .. }) if attempt > self.max_retries => {
+                    return Err(ApiError::Exhausted {
+                        what: what.into(),
+                        attempts: attempt,
+                        error,
+                    })
+                }
+                Err(Failure::Transient { error, retry_after }) => {
+                    // honor the server's wishes, if any
+                    let delay = retry_after.unwrap_or_else(|| self.delay(attempt));
+                    log::warn!("{what} failed ({error:#}), retrying in {delay:?}");
+                    time::sleep(delay).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    const POLICY: RetryPolicy = RetryPolicy {
+        max_retries: 3,
+        initial_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(3),
+    };
+
+    #[test]
+    fn test_delay() {
+        assert_eq!(POLICY.delay(1), Duration::from_millis(1));
+        assert_eq!(POLICY.delay(2), Duration::from_millis(2));
+        assert_eq!(POLICY.delay(3), Duration::from_millis(3));
+        assert_eq!(POLICY.delay(40), Duration::from_millis(3));
+    }
+
+    #[tokio::test]
+    async fn test_retries() {
+        // succeeds on the third attempt
+        let mut calls = 0;
+        let res = POLICY
+            .run("test", || {
+                calls += 1;
+                let n = calls;
+                async move {
+                    if n < 3 {
+                        Err(Failure::Transient {
+                            error: anyhow!("nope"),
+                            retry_after: None,
+                        })
+                    } else {
+                        Ok(n)
+                    }
+                }
+            })
+            .await;
+        assert_eq!(res.unwrap(), 3);
+
+        // always fails
+        let mut calls = 0;
+        let res: Result<(), _> = POLICY
+            .run("test", || {
+                calls += 1;
+                async {
+                    Err(Failure::Transient {
+                        error: anyhow!("nope"),
+                        retry_after: Some(Duration::from_millis(1)),
+                    })
+                }
+            })
+            .await;
+        assert!(res.unwrap_err().is_transient());
+        assert_eq!(calls, 4);
+
+        // permanent failures aren't retried
+        let mut calls = 0;
+        let res: Result<(), _> = POLICY
+            .run("test", || {
+                calls += 1;
+                async { Err(Failure::Permanent(anyhow!("nope"))) }
+            })
+            .await;
+        assert!(!res.unwrap_err().is_transient());
+        assert_eq!(calls, 1);
+    }
+}
@@ -0,0 +1,364 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+
+/// Templates used when no file is provided
+const DEFAULT_TEMPLATES: &str = include_str!("../templates.toml");
+
+/// Messages the bot can post
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Message {
+    /// Opening line of other messages
+    Greeting,
+    /// Text of the post with the result
+    Result,
+    /// Warning for programs which made no sound
+    Silent,
+    /// Warning for videos which had to be trimmed
+    Trimmed,
+    /// The account is sending too many requests
+    RateLimited,
+    /// The bot is taking too many requests overall
+    Busy,
+    /// The code couldn't be parsed
+    ParseError,
+    /// The server couldn't process the video
+    MediaFailed,
+}
+
+impl Message {
+    const ALL: [Message; 8] = [
+        Message::Greeting,
+        Message::Result,
+        Message::Silent,
+        Message::Trimmed,
+        Message::RateLimited,
+        Message::Busy,
+        Message::ParseError,
+        Message::MediaFailed,
+    ];
+
+    /// Name of the message in the template file
+    fn key(self) -> &'static str {
+        match self {
+            Message::Greeting => "greeting",
+            Message::Result => "result",
+            Message::Silent => "silent",
+            Message::Trimmed => "trimmed",
+            Message::RateLimited => "rate_limited",
+            Message::Busy => "busy",
+            Message::ParseError => "parse_error",
+            Message::MediaFailed => "media_failed",
+        }
+    }
+
+    fn from_key(key: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|m| m.key() == key)
+    }
+
+    /// Placeholders which may be used in the message
+    fn placeholders(self) -> &'static [&'static str] {
+        match self {
+            Message::Greeting => &["user"],
+            Message::Result => &["user", "greeting", "duration", "grid_size", "warnings"],
+            Message::Trimmed => &["user", "greeting", "duration"],
+            Message::ParseError => &["user", "greeting", "error"],
+            Message::Silent | Message::RateLimited | Message::Busy | Message::MediaFailed => {
+                &["user", "greeting"]
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Text(String),
+    Placeholder(String),
+}
+
+/// A message, split into literal text and placeholders
+#[derive(Debug)]
+struct Template(Vec<Segment>);
+
+impl Template {
+    fn parse(text: &str) -> Result<Self> {
+        let mut segments = Vec::new();
+        let mut current = String::new();
+        let mut chars = text.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    current.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    current.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) if c.is_ascii_alphanumeric() || c == '_' => name.push(c),
+                            _ => bail!("Unterminated placeholder {{{name}"),
+                        }
+                    }
+                    if !current.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut current)));
+                    }
+                    segments.push(Segment::Placeholder(name));
+                }
+                '}' => bail!("Unmatched }} (use }}}} for a literal one)"),
+                c => current.push(c),
+            }
+        }
+        if !current.is_empty() {
+            segments.push(Segment::Text(current));
+        }
+
+        Ok(Self(segments))
+    }
+
+    /// Check that only the placeholders which will be filled in are used
+    fn validate(&self, message: Message) -> Result<()> {
+        for segment in &self.0 {
+            if let Segment::Placeholder(name) = segment {
+                if !message.placeholders().contains(&name.as_str()) {
+                    bail!(
+                        "Unknown placeholder {{{name}}} (allowed: {})",
+                        message.placeholders().join(", ")
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn render(&self, values: &[(&str, &str)]) -> String {
+        let mut res = String::new();
+        for segment in &self.0 {
+            match segment {
+                Segment::Text(text) => res.push_str(text),
+                Segment::Placeholder(name) => match values.iter().find(|(k, _)| k == name) {
+                    Some((_, value)) => res.push_str(value),
+                    None => log::warn!("No value provided for {{{name}}}"),
+                },
+            }
+        }
+        res
+    }
+}
+
+/// Layout of the template file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TemplateFile {
+    language: String,
+    messages: HashMap<String, String>,
+    #[serde(default)]
+    translations: HashMap<String, HashMap<String, String>>,
+}
+
+/// A message ready to be posted
+#[derive(Debug)]
+pub struct Rendered {
+    pub text: String,
+    /// Language the message is written in
+    pub language: String,
+}
+
+/// Messages posted by the bot, in the default language and any translations
+#[derive(Debug)]
+pub struct Templates {
+    language: String,
+    messages: HashMap<Message, Template>,
+    translations: HashMap<String, HashMap<Message, Template>>,
+}
+
+fn parse_messages(messages: HashMap<String, String>) -> Result<HashMap<Message, Template>> {
+    messages
+        .into_iter()
+        .map(|(key, text)| {
+            let message =
+                Message::from_key(&key).ok_or_else(|| anyhow!("Unknown message {key}"))?;
+            let template = Template::parse(&text)
+                .and_then(|t| t.validate(message).map(|_| t))
+                .with_context(|| format!("Invalid message {key}"))?;
+            Ok((message, template))
+        })
+        .collect()
+}
+
+impl Templates {
+    /// Load templates from a file, or the built-in ones if `None`
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        match path {
+            Some(path) => Self::parse(&fs::read_to_string(path)?)
+                .with_context(|| format!("Invalid template file {}", path.display())),
+            None => Self::parse(DEFAULT_TEMPLATES),
+        }
+    }
+
+    fn parse(data: &str) -> Result<Self> {
+        let file: TemplateFile = toml::from_str(data)?;
+
+        let messages = parse_messages(file.messages)?;
+        if let Some(missing) = Message::ALL.iter().find(|m| !messages.contains_key(m)) {
+            bail!("Missing message {}", missing.key());
+        }
+
+        let translations = file
+            .translations
+            .into_iter()
+            .map(|(language, messages)| {
+                let messages = parse_messages(messages)
+                    .with_context(|| format!("Invalid translation {language}"))?;
+                Ok((language.to_lowercase(), messages))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            language: file.language,
+            messages,
+            translations,
+        })
+    }
+
+    /// Find the translation of a message in a given language (e.g. `pt-BR`, falling back to `pt`)
+    fn translation(&self, message: Message, language: &str) -> Option<(&str, &Template)> {
+        let language = language.to_lowercase();
+        let primary = language.split(['-', '_']).next().unwrap_or_default();
+
+        [language.as_str(), primary].iter().find_map(|&lang| {
+            self.translations
+                .get_key_value(lang)
+                .and_then(|(lang, messages)| Some((lang.as_str(), messages.get(&message)?)))
+        })
+    }
+
+    fn template(&self, message: Message, language: Option<&str>) -> (&str, &Template) {
+        language
+            .and_then(|lang| self.translation(message, lang))
+            .unwrap_or_else(|| (&self.language, &self.messages[&message]))
+    }
+
+    /// Render a message in the language of the request (if available), filling in `values`
+    pub fn render(
+        &self,
+        message: Message,
+        language: Option<&str>,
+        user: &str,
+        values: &[(&str, &str)],
+    ) -> Rendered {
+        let (_, greeting) = self.template(Message::Greeting, language);
+        let greeting = greeting.render(&[("user", user)]);
+
+        let (language, template) = self.template(message, language);
+        let values = [("user", user), ("greeting", &greeting)]
+            .into_iter()
+            .chain(values.iter().copied())
+            .collect::<Vec<_>>();
+
+        Rendered {
+            // leave no trailing space behind if e.g. there are no warnings
+            text: template.render(&values).trim_end().to_string(),
+            language: language.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_template() {
+        let template = Template::parse("Hi {user}! {{literal}}").unwrap();
+        assert_eq!(
+            template.0,
+            vec![
+                Segment::Text("Hi ".into()),
+                Segment::Placeholder("user".into()),
+                Segment::Text("! {literal}".into()),
+            ]
+        );
+
+        assert!(Template::parse("Hi {user").is_err());
+        assert!(Template::parse("Hi user}").is_err());
+        assert!(Template::parse("Hi {user}")
+            .unwrap()
+            .validate(Message::Greeting)
+            .is_ok());
+        assert!(Template::parse("{error}")
+            .unwrap()
+            .validate(Message::Greeting)
+            .is_err());
+    }
+
+    #[test]
+    fn test_default_templates() {
+        let templates = Templates::load(None).unwrap();
+        let rendered =
+            templates.render(Message::RateLimited, Some("en"), "someone@example.com", &[]);
+        assert!(rendered.text.starts_with("Hey there"));
+        assert!(!rendered.text.contains('{'));
+        assert_eq!(rendered.language, "en");
+
+        let rendered = templates.render(
+            Message::Result,
+            None,
+            "someone@example.com",
+            &[("warnings", "")],
+        );
+        assert_eq!(
+            rendered.text,
+            "I ran @someone@example.com's program and here's the result!"
+        );
+    }
+
+    #[test]
+    fn test_translations() {
+        let templates = Templates::parse(
+            r#"
+            language = "en"
+
+            [messages]
+            greeting = "Hi"
+            result = "{greeting} {user}, it took {duration}"
+            silent = "Silent"
+            trimmed = "Trimmed"
+            rate_limited = "Slow down"
+            busy = "Busy"
+            parse_error = "Error: {error}"
+            media_failed = "Failed"
+
+            [translations.pt]
+            greeting = "Olá"
+            result = "{greeting} {user}, demorou {duration}"
+            "#,
+        )
+        .unwrap();
+
+        let values = [("duration", "10s")];
+        let rendered = templates.render(Message::Result, Some("pt-BR"), "ana", &values);
+        assert_eq!(rendered.text, "Olá ana, demorou 10s");
+        assert_eq!(rendered.language, "pt");
+
+        // missing translations fall back to the default language
+        let rendered = templates.render(Message::Silent, Some("pt"), "ana", &[]);
+        assert_eq!(rendered.text, "Silent");
+        assert_eq!(rendered.language, "en");
+
+        let rendered = templates.render(Message::Result, Some("de"), "ana", &values);
+        assert_eq!(rendered.text, "Hi ana, it took 10s");
+
+        // incomplete or invalid files are rejected
+        assert!(Templates::parse("language = \"en\"\n[messages]\ngreeting = \"Hi\"").is_err());
+        assert!(
+            Templates::parse("language = \"en\"\n[messages]\ngreeting = \"Hi {error}\"").is_err()
+        );
+    }
+}
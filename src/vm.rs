@@ -10,11 +10,23 @@ use uxn::{Backend, Uxn, UxnRam};
 use varvara::{Output, Varvara};
 use zerocopy::IntoBytes;
 
+use crate::audio::{AudioStats, MixConfig, WavWriter};
+
+/// Outcome of a VM run
+pub struct RunOutput {
+    /// Size of the screen
+    pub size: (u16, u16),
+    /// Statistics of the generated audio
+    pub audio: AudioStats,
+}
+
 pub struct VMWrapper<'t> {
     native: bool,
     args: &'t Vec<String>,
+    mix: &'t MixConfig,
     screen_dir: PathBuf,
     audio_file: PathBuf,
+    stems_dir: Option<PathBuf>,
 }
 
 impl<'t> VMWrapper<'t> {
@@ -22,12 +34,16 @@ impl<'t> VMWrapper<'t> {
         screen_dir: impl AsRef<Path>,
         audio_file: impl AsRef<Path>,
         args: &'t Vec<String>,
+        mix: &'t MixConfig,
         native: bool,
+        stems_dir: Option<&Path>,
     ) -> Self {
         Self {
             screen_dir: PathBuf::from(screen_dir.as_ref()),
             audio_file: PathBuf::from(audio_file.as_ref()),
+            stems_dir: stems_dir.map(PathBuf::from),
             args,
+            mix,
             native,
         }
     }
@@ -37,7 +53,7 @@ impl<'t> VMWrapper<'t> {
         rom_path: impl AsRef<Path>,
         input: impl Iterator<Item = &'t [char]>,
         n_frames: usize,
-    ) -> Result<(u16, u16)> {
+    ) -> Result<RunOutput> {
         let mut f = std::fs::File::open(rom_path.as_ref())
             .with_context(|| format!("failed to open {:?}", rom_path.as_ref()))?;
 
@@ -86,22 +102,47 @@ impl<'t> VMWrapper<'t> {
 
         let streams = dev.audio_streams();
         let mut audio_tmp = [0f32; 1470];
+        let mut audio_stats = AudioStats::default();
 
         let mut audio_f =
             File::create(self.audio_file.clone()).context("Failed to open output audio file")?;
 
+        // one WAV file per channel, plus the mixdown
+        let mut stems = match &self.stems_dir {
+            Some(dir) => {
+                let mut writers = Vec::new();
+                for n in 1..=streams.len() {
+                    writers.push(
+                        WavWriter::create(dir.join(format!("channel_{n}.wav")))
+                            .context("Failed to open stem file")?,
+                    );
+                }
+                writers.push(
+                    WavWriter::create(dir.join("mix.wav")).context("Failed to open stem file")?,
+                );
+                writers
+            }
+            None => Vec::new(),
+        };
+
         for frame_n in 0..n_frames {
             let mut audio_mixdown = [0f32; 1470];
 
             dev.audio(&mut vm);
             dev.redraw(&mut vm);
 
-            for stream in streams.iter() {
+            for (n, stream) in streams.iter().enumerate() {
                 stream.lock().unwrap().next(&mut audio_tmp);
-                for (n, v) in audio_mixdown.iter_mut().enumerate() {
-                    *v += audio_tmp[n];
+                self.mix.add(&mut audio_mixdown, &audio_tmp);
+                if let Some(stem) = stems.get_mut(n) {
+                    stem.write(&audio_tmp).context("Can't write to stem file")?;
                 }
             }
+            self.mix.master(&mut audio_mixdown);
+            audio_stats.update(&audio_mixdown);
+            if let Some(stem) = stems.get_mut(streams.len()) {
+                stem.write(&audio_mixdown).context("Can't write to stem file")?;
+            }
 
             audio_f
                 .write(audio_mixdown.as_bytes())
@@ -118,8 +159,15 @@ impl<'t> VMWrapper<'t> {
             f.flush()?;
         }
 
+        for stem in stems {
+            stem.finish().context("Can't write to stem file")?;
+        }
+
         let Output { size, .. } = dev.output(&vm);
 
-        Ok(size)
+        Ok(RunOutput {
+            size,
+            audio: audio_stats,
+        })
     }
 }